@@ -1,7 +1,12 @@
 use core::str;
 use std::{
-    io::{self, Write},
+    borrow::Cow,
+    collections::HashMap,
+    io::{self, Read, Write},
     net::{SocketAddr, TcpStream},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
     thread, time,
 };
 
@@ -16,16 +21,44 @@ use crossterm::{
     tty::IsTty,
     QueueableCommand,
 };
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    StreamOwned,
+};
 
-use server::messages::{self, MessageToClient, PeerMessage};
+use server::{
+    auth::Token,
+    e2e::{EncryptedPayload, KeyCheck, KeyPair, KnownKeys, PublicKey},
+    framing::{self, FrameDecoder},
+    messages::{
+        self, Capability, Hello, HelloReply, MessageAuthor, MessageToClient, MessageToServer,
+        PeerMessage, Recipient, ServerMessage, SignedToken, WireMessage, MIN_PROTOCOL_VERSION,
+        PROTOCOL_VERSION,
+    },
+    session::SessionId,
+    transport,
+};
 
-// TODO: Read message struct directly from stream, without buffer
 // TODO: Separate read message from stream and process it
-// TODO: Send serialized message struct to server
 // TODO: Better authentication step
 // TODO: UI: Wrap lines
 // TODO: UI: Persistent prompt content on resize
 
+/// Size of the buffer used to read from stream
+const READ_BUFFER_SIZE: usize = 4 * 1024; // 4kb
+
+/// Maximum time to wait for the server handshake reply
+const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Interval between pings keeping the connection from idling out
+const KEEPALIVE_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+/// Capabilities supported by this client
+const CAPABILITIES: &[Capability] = &[Capability::Rooms, Capability::Encryption];
+
 /// Get local datetime from message timestamp
 fn datetime(timestamp: i64) -> Result<DateTime<chrono::Local>> {
     chrono::Local
@@ -70,6 +103,41 @@ impl Prompt {
         &self.content
     }
 
+    /// Text shown on screen, with the passwords of account commands masked
+    fn masked(&self) -> Cow<'_, str> {
+        let Some(command) = self.content.strip_prefix('/') else {
+            return Cow::Borrowed(&self.content);
+        };
+        // Words shown before the passwords, counting the command itself
+        let shown = match command.split_whitespace().next() {
+            Some("login" | "register") => 2,
+            Some("passwd") => 1,
+            _ => return Cow::Borrowed(&self.content),
+        };
+        let mut words = 0;
+        let mut in_word = false;
+        Cow::Owned(
+            self.content
+                .chars()
+                .map(|c| {
+                    if c.is_whitespace() {
+                        in_word = false;
+                        return c;
+                    }
+                    if !in_word {
+                        in_word = true;
+                        words += 1;
+                    }
+                    if words > shown {
+                        '*'
+                    } else {
+                        c
+                    }
+                })
+                .collect(),
+        )
+    }
+
     fn resize(&mut self, width: u16) {
         self.max_width = width - 3;
         self.content.truncate(self.max_width as usize);
@@ -111,7 +179,20 @@ enum Message {
     Sent {
         timestamp: i64,
         content: PeerMessage,
+        /// Peer addressed by a direct message
+        recipient: Option<Recipient>,
+    },
+    /// Direct message encrypted end-to-end, shown decrypted
+    Private {
+        timestamp: i64,
+        /// Display name of the peer
+        peer: String,
+        text: String,
+        /// Whether the peer sent the message to this client
+        incoming: bool,
     },
+    /// Notice from this client itself
+    Notice { timestamp: i64, text: String },
 }
 
 impl Message {
@@ -119,31 +200,369 @@ impl Message {
         match self {
             Message::Received(message) => match message.author {
                 messages::MessageAuthor::Server(ref content) => match content {
-                    messages::ServerMessage::Ban(reason) => {
-                        Ok(format!("You have been banned. Reason: {reason}"))
-                    }
+                    messages::ServerMessage::Banned {
+                        reason,
+                        remaining_secs,
+                    } => Ok(format!(
+                        "[{dt}] Server: You are banned {time}. Reason: {reason}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S"),
+                        time = match remaining_secs {
+                            Some(secs) => format!("for {secs} seconds"),
+                            None => "permanently".to_owned(),
+                        }
+                    )),
+                    messages::ServerMessage::Kicked { reason } => Ok(format!(
+                        "[{dt}] Server: You have been kicked. Reason: {reason}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S"),
+                        reason = reason.as_deref().unwrap_or("No reason given")
+                    )),
+                    messages::ServerMessage::Muted {
+                        remaining_secs,
+                        reason,
+                    } => Ok(format!(
+                        "[{dt}] Server: You are muted for {remaining_secs} seconds{reason}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S"),
+                        reason = reason
+                            .as_ref()
+                            .map(|reason| format!(". Reason: {reason}"))
+                            .unwrap_or_default()
+                    )),
+                    messages::ServerMessage::Unmuted => Ok(format!(
+                        "[{dt}] Server: You are no longer muted",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::AuthChallenge { .. } => Ok(format!(
+                        "[{dt}] Server: Authentication requested",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::CertificateAccepted { identity } => Ok(format!(
+                        "[{dt}] Server: Authenticated by client certificate as {identity}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::Motd(motd) => Ok(format!(
+                        "[{dt}] Message of the day: {motd}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::LoggedIn { username, role } => Ok(format!(
+                        "[{dt}] Server: Logged in as {username} ({role})",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::RoleChanged(role) => Ok(format!(
+                        "[{dt}] Server: You are now a {role}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
                     messages::ServerMessage::Text(text) => Ok(format!(
                         "[{dt}] Server: {text}",
                         dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
                     )),
+                    messages::ServerMessage::Connected { id, name } => Ok(format!(
+                        "[{dt}] Server: Connected as {name} (#{id})",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::PeerKey { id, nickname, key } => Ok(format!(
+                        "[{dt}] Server: {name} (#{id}) published encryption key {fingerprint}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S"),
+                        name = nickname.clone().unwrap_or_else(|| format!("User {id}")),
+                        fingerprint = key.fingerprint()
+                    )),
+                    messages::ServerMessage::Renamed { id, old, new } => Ok(format!(
+                        "[{dt}] Server: {old} (#{id}) is now known as {new}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::Error(text) => Ok(format!(
+                        "[{dt}] Error: {text}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::Pong => Ok(format!(
+                        "[{dt}] Server: Pong",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::JoinedRoom(room) => Ok(format!(
+                        "[{dt}] Server: You are now in room {room}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::PeerJoined { id, name } => Ok(format!(
+                        "[{dt}] Server: {name} (#{id}) joined the room",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::PeerLeft { id, name } => Ok(format!(
+                        "[{dt}] Server: {name} (#{id}) left the room",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::UserList { room, users } => Ok(format!(
+                        "[{dt}] Server: Users in {room}: {users}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S"),
+                        users = users
+                            .iter()
+                            .map(|user| format!("{name} (#{id})", name = user.name, id = user.id))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )),
+                    messages::ServerMessage::Help(commands) => match commands.as_slice() {
+                        [command] => Ok(format!(
+                            "[{dt}] Server: {usage}: {description}",
+                            dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S"),
+                            usage = command.usage,
+                            description = command.description
+                        )),
+                        commands => Ok(format!(
+                            "[{dt}] Server: Commands: {names} (/help <command> for details)",
+                            dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S"),
+                            names = commands
+                                .iter()
+                                .map(|command| format!("/{name}", name = command.name))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        )),
+                    },
+                    messages::ServerMessage::CommandError { command, error } => Ok(format!(
+                        "[{dt}] Error: /{command}: {error}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::ServerMessage::BanList(bans) => Ok(format!(
+                        "[{dt}] Server: Bans: {bans}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S"),
+                        bans = bans
+                            .iter()
+                            .map(|ban| format!(
+                                "{range}{account} ({time}, by {issued_by}: {reason})",
+                                range = ban.range,
+                                account = ban
+                                    .account
+                                    .as_ref()
+                                    .map(|account| format!(" and account {account}"))
+                                    .unwrap_or_default(),
+                                time = match ban.remaining_secs() {
+                                    Some(secs) => format!("{secs}s left"),
+                                    None => "permanent".to_owned(),
+                                },
+                                issued_by = ban.issued_by,
+                                reason = ban.reason
+                            ))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )),
+                    messages::ServerMessage::RoomList(rooms) => Ok(format!(
+                        "[{dt}] Server: Rooms: {rooms}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S"),
+                        rooms = rooms
+                            .iter()
+                            .map(|room| format!(
+                                "{name} ({members})",
+                                name = room.name,
+                                members = room.members
+                            ))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )),
                 },
-                messages::MessageAuthor::Peer { id, ref content } => match content {
+                messages::MessageAuthor::Peer {
+                    ref id,
+                    ref name,
+                    ref content,
+                } => match content {
                     messages::PeerMessage::Text(text) => Ok(format!(
-                        "[{dt}] User {id}: {text}",
+                        "[{dt}] {name}: {text}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::PeerMessage::Direct(text) => Ok(format!(
+                        "[{dt}] [private] {name} (#{id}) -> you: {text}",
+                        dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                    )),
+                    messages::PeerMessage::Encrypted(_) => Ok(format!(
+                        "[{dt}] [encrypted] {name} (#{id}) -> you: <unable to decrypt>",
                         dt = datetime(message.timestamp)?.format("%d/%m/%Y %H:%M:%S")
                     )),
                 },
             },
-            Message::Sent { timestamp, content } => match content {
-                PeerMessage::Text(text) => Ok(format!(
+            Message::Sent {
+                timestamp,
+                content,
+                recipient,
+            } => match (content, recipient) {
+                (PeerMessage::Text(text), _) => Ok(format!(
                     "[{dt}] You: {text}",
                     dt = datetime(*timestamp)?.format("%d/%m/%Y %H:%M:%S")
                 )),
+                (PeerMessage::Direct(text), Some(recipient)) => Ok(format!(
+                    "[{dt}] [private] You -> {recipient}: {text}",
+                    dt = datetime(*timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                )),
+                (PeerMessage::Direct(text), None) => Ok(format!(
+                    "[{dt}] [private] You: {text}",
+                    dt = datetime(*timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                )),
+                (PeerMessage::Encrypted(_), _) => Ok(format!(
+                    "[{dt}] [encrypted] You: <encrypted>",
+                    dt = datetime(*timestamp)?.format("%d/%m/%Y %H:%M:%S")
+                )),
             },
+            Message::Private {
+                timestamp,
+                peer,
+                text,
+                incoming,
+            } => {
+                let dt = datetime(*timestamp)?.format("%d/%m/%Y %H:%M:%S");
+                if *incoming {
+                    Ok(format!("[{dt}] [encrypted] {peer} -> you: {text}"))
+                } else {
+                    Ok(format!("[{dt}] [encrypted] You -> {peer}: {text}"))
+                }
+            }
+            Message::Notice { timestamp, text } => Ok(format!(
+                "[{dt}] {text}",
+                dt = datetime(*timestamp)?.format("%d/%m/%Y %H:%M:%S")
+            )),
         }
     }
 }
 
+/// End-to-end encryption of direct messages
+#[derive(Debug)]
+struct Encryption {
+    key_pair: KeyPair,
+    /// Keys of peers seen before, to warn when they change
+    known_keys: KnownKeys,
+    /// Published keys of connected peers along with their nicknames
+    peers: HashMap<SessionId, (Option<String>, PublicKey)>,
+}
+
+impl Encryption {
+    fn new(key_pair: KeyPair, known_keys: KnownKeys) -> Self {
+        Self {
+            key_pair,
+            known_keys,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Remember key published by peer, noticing if it is new or changed
+    fn add_peer(
+        &mut self,
+        timestamp: i64,
+        id: SessionId,
+        nickname: Option<String>,
+        key: PublicKey,
+    ) -> Option<Message> {
+        self.peers.insert(id, (nickname.clone(), key));
+        self.check_key(timestamp, &nickname?, key)
+    }
+
+    /// Track nickname change of peer, checking its key against the one known for the new name
+    fn rename_peer(&mut self, timestamp: i64, id: SessionId, nickname: &str) -> Option<Message> {
+        let (peer_nickname, key) = self.peers.get_mut(&id)?;
+        *peer_nickname = Some(nickname.to_owned());
+        let key = *key;
+        self.check_key(timestamp, nickname, key)
+    }
+
+    /// Compare key of peer with the one seen before under the same nickname
+    fn check_key(&mut self, timestamp: i64, nickname: &str, key: PublicKey) -> Option<Message> {
+        let text = match self.known_keys.check(nickname, key) {
+            Ok(KeyCheck::Known) => return None,
+            Ok(KeyCheck::New) => format!(
+                "Encryption key of {nickname}: {fingerprint}",
+                fingerprint = key.fingerprint()
+            ),
+            Ok(KeyCheck::Changed { previous }) => format!(
+                "WARNING: Encryption key of {nickname} changed from {previous} to {current}! \
+                Verify it with them.",
+                previous = previous.fingerprint(),
+                current = key.fingerprint()
+            ),
+            Err(e) => format!("Unable to check encryption key of {nickname}: {e:#}"),
+        };
+        Some(Message::Notice { timestamp, text })
+    }
+
+    /// Peer addressed by nickname or ID
+    fn find_peer(&self, recipient: &Recipient) -> Option<(SessionId, String, PublicKey)> {
+        self.peers
+            .iter()
+            .find(|(id, (nickname, _))| match recipient {
+                Recipient::Id(other) => *id == other,
+                Recipient::Nickname(other) => nickname
+                    .as_ref()
+                    .is_some_and(|nickname| nickname.to_lowercase() == other.to_lowercase()),
+            })
+            .map(|(id, (nickname, key))| {
+                let name = nickname.clone().unwrap_or_else(|| format!("User {id}"));
+                (*id, name, *key)
+            })
+    }
+
+    /// Encrypt text for recipient, returning the message to send and its echo
+    fn encrypt(
+        &self,
+        timestamp: i64,
+        recipient: &str,
+        text: &str,
+    ) -> Result<(MessageToServer, Message)> {
+        let (id, peer, key) = self
+            .find_peer(&Recipient::parse(recipient))
+            .with_context(|| format!("No encryption key known for {recipient}"))?;
+        let payload = self.key_pair.encrypt(&key, text)?;
+        let message = MessageToServer::Encrypted {
+            recipient: Recipient::Id(id),
+            payload,
+        };
+        let echo = Message::Private {
+            timestamp,
+            peer,
+            text: text.to_owned(),
+            incoming: false,
+        };
+        Ok((message, echo))
+    }
+
+    /// Decrypt direct message sent by peer
+    fn decrypt(
+        &self,
+        timestamp: i64,
+        id: SessionId,
+        name: &str,
+        payload: &EncryptedPayload,
+    ) -> Message {
+        let text = self
+            .peers
+            .get(&id)
+            .context("Sender did not publish a key")
+            .and_then(|(_, key)| self.key_pair.decrypt(key, payload));
+        match text {
+            Ok(text) => Message::Private {
+                timestamp,
+                peer: format!("{name} (#{id})"),
+                text,
+                incoming: true,
+            },
+            Err(e) => Message::Notice {
+                timestamp,
+                text: format!("Unable to decrypt message from {name} (#{id}): {e:#}"),
+            },
+        }
+    }
+
+    /// Fingerprint of own key, or of the key of a peer
+    fn fingerprint(&self, timestamp: i64, recipient: &str) -> Message {
+        let text = if recipient.is_empty() {
+            format!(
+                "Your encryption key: {fingerprint}",
+                fingerprint = self.key_pair.public.fingerprint()
+            )
+        } else {
+            match self.find_peer(&Recipient::parse(recipient)) {
+                Some((_, peer, key)) => format!(
+                    "Encryption key of {peer}: {fingerprint}",
+                    fingerprint = key.fingerprint()
+                ),
+                None => format!("No encryption key known for {recipient}"),
+            }
+        };
+        Message::Notice { timestamp, text }
+    }
+}
+
 /// Application state
 #[derive(Debug, PartialEq, Eq)]
 enum State {
@@ -161,7 +580,14 @@ where
     height: u16,
     prompt: Prompt,
     chat: Vec<Message>,
-    stream: TcpStream,
+    stream: Connection,
+    decoder: FrameDecoder,
+    outgoing: Vec<u8>,
+    last_ping: time::Instant,
+    /// Room the client is currently in
+    room: Option<String>,
+    /// Encryption of direct messages, if the server supports it
+    encryption: Option<Encryption>,
     state: State,
 }
 
@@ -169,19 +595,35 @@ impl<T> ClientInterface<T>
 where
     T: io::Write + QueueableCommand + IsTty,
 {
-    fn new(output: T, stream: TcpStream) -> Result<Self> {
+    fn new(output: T, stream: Connection, encryption: Option<Encryption>) -> Result<Self> {
         if !output.is_tty() {
             bail!("Output is not tty")
         }
 
         let (width, height) = terminal::size()?;
+        let mut chat = Vec::new();
+        if let Some(encryption) = &encryption {
+            chat.push(Message::Notice {
+                timestamp: chrono::Local::now().timestamp(),
+                text: format!(
+                    "Send end-to-end encrypted messages with /emsg <recipient> <text>. \
+                    Your key: {fingerprint}",
+                    fingerprint = encryption.key_pair.public.fingerprint()
+                ),
+            });
+        }
         Ok(Self {
             output,
             width,
             height,
             prompt: Prompt::new(width),
-            chat: Vec::new(),
+            chat,
             stream,
+            decoder: FrameDecoder::new(),
+            outgoing: Vec::new(),
+            last_ping: time::Instant::now(),
+            room: None,
+            encryption,
             state: State::Default,
         })
     }
@@ -213,12 +655,21 @@ where
     }
 
     fn queue_draw_prompt(&mut self) -> Result<&mut T> {
+        // Separator showing the current room
+        let title = match &self.room {
+            Some(room) => format!("━━ #{room} "),
+            None => String::new(),
+        };
+        let separator = format!(
+            "{title}{line}",
+            line = "━".repeat((self.width as usize).saturating_sub(title.chars().count()))
+        );
         self.output
             .queue(MoveTo(0, self.height - 2))?
-            .queue(Print("━".repeat(self.width as usize)))?
+            .queue(Print(separator))?
             .queue(MoveTo(0, self.height - 1))?
             .queue(Print(" > "))?
-            .queue(Print(self.prompt.text()))
+            .queue(Print(self.prompt.masked()))
             .context("Unable to draw prompt")
     }
 
@@ -254,7 +705,17 @@ where
 
     fn handle_event(&mut self) -> Result<()> {
         let new_event = event::read()?;
-        log::debug!("Handling event: {new_event:?}");
+        // Typed text may hold passwords, so it is never logged
+        if !matches!(
+            new_event,
+            Event::Paste(_)
+                | Event::Key(event::KeyEvent {
+                    code: KeyCode::Char(_),
+                    ..
+                })
+        ) {
+            log::debug!("Handling event: {new_event:?}");
+        }
 
         match new_event {
             Event::Resize(width, height) => {
@@ -277,18 +738,56 @@ where
                 KeyCode::Backspace => {
                     let _ = self.prompt.pop();
                 }
-                KeyCode::Enter => {
-                    if !self.prompt.is_empty() {
-                        match self.stream.write(self.prompt.text().as_bytes()) {
-                            Err(e) => log::error!("Unable to send data: {e}"),
-                            Ok(n) => log::info!("Successfully sent {n} bytes"),
-                        }
-                        self.chat.push(Message::Sent {
-                            timestamp: chrono::Local::now().timestamp(),
-                            content: PeerMessage::Text(self.prompt.text().to_string()),
-                        });
+                KeyCode::Enter if !self.prompt.is_empty() => {
+                    // Encryption commands are handled by the client itself
+                    let input = self.prompt.text().to_owned();
+                    if let Some(message) = self.local_command(&input) {
+                        self.chat.push(message);
                         self.prompt.clear();
+                        return Ok(());
                     }
+                    if let Some(message) = MessageToServer::from_input(&input) {
+                        let has_password = matches!(
+                            message,
+                            MessageToServer::Register { .. }
+                                | MessageToServer::Login { .. }
+                                | MessageToServer::ChangePassword { .. }
+                        );
+                        if has_password && matches!(self.stream, Connection::Plain(_)) {
+                            log::warn!("Sending password over an unencrypted connection");
+                            self.chat.push(Message::Notice {
+                                timestamp: chrono::Local::now().timestamp(),
+                                text: "Warning: password sent over an unencrypted connection"
+                                    .to_owned(),
+                            });
+                        }
+                        match self.send_message(&message) {
+                            Err(e) => log::error!("Unable to send message: {e}"),
+                            Ok(()) => log::info!("Successfully queued {message:?}"),
+                        }
+                        let timestamp = chrono::Local::now().timestamp();
+                        match message {
+                            MessageToServer::Text(text) => self.chat.push(Message::Sent {
+                                timestamp,
+                                content: PeerMessage::Text(text),
+                                recipient: None,
+                            }),
+                            // Echo direct messages, the server validates the rest
+                            MessageToServer::Command { name, args } if name == "msg" => {
+                                if let Some((recipient, text)) =
+                                    args.split_once(char::is_whitespace)
+                                {
+                                    self.chat.push(Message::Sent {
+                                        timestamp,
+                                        content: PeerMessage::Direct(text.trim().to_owned()),
+                                        recipient: Some(Recipient::parse(recipient)),
+                                    })
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    self.prompt.clear();
                 }
                 _ => {}
             },
@@ -297,26 +796,124 @@ where
         Ok(())
     }
 
+    /// Run command handled by the client itself, returning the message to show.
+    /// Returns `None` if the input is not such a command.
+    fn local_command(&mut self, input: &str) -> Option<Message> {
+        let command = input.strip_prefix('/')?.trim_start();
+        let (name, args) = command
+            .split_once(char::is_whitespace)
+            .unwrap_or((command, ""));
+        if !matches!(name, "emsg" | "fingerprint") {
+            return None;
+        }
+        let args = args.trim();
+        let timestamp = chrono::Local::now().timestamp();
+        let notice = |text: String| Message::Notice { timestamp, text };
+        let Some(encryption) = &self.encryption else {
+            return Some(notice(
+                "Server does not support encrypted messages".to_owned(),
+            ));
+        };
+        if name == "fingerprint" {
+            return Some(encryption.fingerprint(timestamp, args));
+        }
+        let (recipient, text) = args
+            .split_once(char::is_whitespace)
+            .map_or((args, ""), |(recipient, text)| (recipient, text.trim()));
+        if recipient.is_empty() || text.is_empty() {
+            return Some(notice("Usage: /emsg <recipient> <text...>".to_owned()));
+        }
+        let (message, echo) = match encryption.encrypt(timestamp, recipient, text) {
+            Ok(encrypted) => encrypted,
+            Err(e) => return Some(notice(format!("Error: /emsg: {e:#}"))),
+        };
+        Some(match self.send_message(&message) {
+            Ok(()) => echo,
+            Err(e) => notice(format!("Unable to send encrypted message: {e:#}")),
+        })
+    }
+
+    /// Queue message to be sent to server
+    fn send_message(&mut self, message: &MessageToServer) -> Result<()> {
+        let frame = framing::encode_frame(&message.encode()?)?;
+        self.outgoing.extend_from_slice(&frame);
+        self.flush_outgoing()
+    }
+
+    /// Write as much of the outgoing data as the stream accepts
+    fn flush_outgoing(&mut self) -> Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => bail!("Unable to write to stream"),
+                Ok(n) => {
+                    log::debug!("Successfully sent {n} bytes");
+                    self.outgoing.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e).context("Unable to write to stream"),
+            }
+        }
+        Ok(())
+    }
+
     /// Read incoming data from stream
     fn read_stream(&mut self) -> Result<()> {
-        match MessageToClient::read_from(&self.stream) {
-            Err(e) => {
-                // Ignore `WouldBlock` errors
-                if let ciborium::de::Error::Io(err) = e {
-                    if err.kind() == io::ErrorKind::WouldBlock {
-                        Ok(())
-                    } else {
-                        Err(err).context("Unable to read from stream due to IO error")
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => bail!("Server closed the connection"),
+                Ok(n) => self.decoder.extend(&buffer[..n]),
+                // No more data available for now
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e).context("Unable to read from stream"),
+            }
+        }
+
+        while let Some(payload) = self.decoder.next_frame()? {
+            let message = MessageToClient::decode(&payload)?;
+            // Keepalive replies are not shown in the chat
+            match (&message.author, &mut self.encryption) {
+                (MessageAuthor::Server(ServerMessage::Pong), _) => continue,
+                (MessageAuthor::Server(ServerMessage::JoinedRoom(room)), _) => {
+                    self.room = Some(room.clone());
+                }
+                // Keys are only shown when new or changed
+                (
+                    MessageAuthor::Server(ServerMessage::PeerKey { id, nickname, key }),
+                    Some(encryption),
+                ) => {
+                    if let Some(notice) =
+                        encryption.add_peer(message.timestamp, *id, nickname.clone(), *key)
+                    {
+                        self.chat.push(notice);
                     }
-                } else {
-                    Err(e).context("Unable to read from stream due to parsing error")
+                    continue;
                 }
+                (
+                    MessageAuthor::Server(ServerMessage::Renamed { id, new, .. }),
+                    Some(encryption),
+                ) => {
+                    if let Some(notice) = encryption.rename_peer(message.timestamp, *id, new) {
+                        self.chat.push(notice);
+                    }
+                }
+                (
+                    MessageAuthor::Peer {
+                        id,
+                        name,
+                        content: PeerMessage::Encrypted(payload),
+                    },
+                    Some(encryption),
+                ) => {
+                    self.chat
+                        .push(encryption.decrypt(message.timestamp, *id, name, payload));
+                    continue;
+                }
+                _ => {}
             }
-            Ok(message) => {
-                self.chat.push(Message::Received(message));
-                Ok(())
-            }
+            self.chat.push(Message::Received(message));
         }
+        Ok(())
     }
 
     fn run(&mut self) -> Result<()> {
@@ -339,10 +936,14 @@ where
                         }
                     }
 
-                    if let Err(e) = self.read_stream() {
-                        log::error!("Error reading from stream: {e}");
-                        continue;
-                    };
+                    // Keep connection alive
+                    if self.last_ping.elapsed() >= KEEPALIVE_INTERVAL {
+                        self.send_message(&MessageToServer::Ping)?;
+                        self.last_ping = time::Instant::now();
+                    }
+
+                    self.flush_outgoing()?;
+                    self.read_stream()?;
 
                     self.draw_main()?;
 
@@ -354,6 +955,290 @@ where
     }
 }
 
+/// Announce protocol support to the server and wait for the negotiated result.
+/// Returns the capabilities shared with the server.
+fn handshake(stream: &mut Connection) -> Result<Vec<Capability>> {
+    stream.tcp().set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    Hello::new(CAPABILITIES.to_vec())
+        .write_to(&mut *stream)
+        .context("Unable to send hello")?;
+    let reply = HelloReply::read_from(&mut *stream)
+        .context("Unable to read hello reply")?
+        .context("Server closed the connection during handshake")?;
+    stream.tcp().set_read_timeout(None)?;
+
+    match reply {
+        HelloReply::Accepted {
+            version,
+            capabilities,
+        } => {
+            log::info!("Negotiated protocol version {version} with capabilities {capabilities:?}");
+            Ok(capabilities)
+        }
+        HelloReply::Rejected {
+            version,
+            min_version,
+            reason,
+        } => bail!(
+            "Incompatible server: server supports protocol versions {min_version}-{version}, \
+            this client supports {MIN_PROTOCOL_VERSION}-{PROTOCOL_VERSION} ({reason})"
+        ),
+    }
+}
+
+/// Credential answering the server authentication challenge
+#[derive(Debug)]
+enum Credential {
+    /// Shared access token, proven without sending it
+    Shared(Token),
+    /// Token signed by the server administrators, sent as is and so over TLS only
+    Signed(String),
+}
+
+impl FromStr for Credential {
+    type Err = anyhow::Error;
+
+    /// Signed tokens separate claims and signature with a dot, shared tokens are hex
+    fn from_str(s: &str) -> Result<Self> {
+        if s.contains('.') {
+            Ok(Self::Signed(s.trim().to_owned()))
+        } else {
+            s.parse().map(Self::Shared)
+        }
+    }
+}
+
+/// Answer the server authentication challenge, without sending shared tokens themselves,
+/// unless the server accepted the client certificate instead
+fn authenticate(
+    stream: &mut Connection,
+    credential: Option<&Credential>,
+    nickname: Option<String>,
+) -> Result<()> {
+    stream.tcp().set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let challenge = MessageToClient::read_from(&mut *stream)
+        .context("Unable to read authentication challenge")?
+        .context("Server closed the connection before authentication")?;
+    stream.tcp().set_read_timeout(None)?;
+
+    match challenge.author {
+        MessageAuthor::Server(ServerMessage::AuthChallenge { nonce }) => {
+            let message = match credential.context("Server requires an access token")? {
+                Credential::Shared(token) => MessageToServer::Auth {
+                    response: token.respond(&nonce),
+                    nickname,
+                },
+                Credential::Signed(token) => {
+                    if matches!(stream, Connection::Plain(_)) {
+                        bail!("Signed tokens are only sent over TLS, connect with --ca or --pin")
+                    }
+                    if nickname.is_some() {
+                        log::warn!("Nickname ignored, the signed token names you");
+                    }
+                    MessageToServer::AuthSigned {
+                        token: SignedToken(token.clone()),
+                    }
+                }
+            };
+            message
+                .write_to(stream)
+                .context("Unable to send authentication response")
+        }
+        MessageAuthor::Server(ServerMessage::CertificateAccepted { identity }) => {
+            log::info!("Authenticated by client certificate as {identity}");
+            if nickname.is_some() {
+                log::warn!("Nickname ignored, the client certificate names you {identity}");
+            }
+            Ok(())
+        }
+        author => bail!("Expected authentication challenge, got {author:?}"),
+    }
+}
+
+/// Connection to the server, encrypted or not
+#[derive(Debug)]
+enum Connection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Connection {
+    /// Connect to server, over TLS if a CA file or pinned certificate is given
+    fn open(args: &Args) -> Result<Self> {
+        let stream = TcpStream::connect(args.addr)
+            .with_context(|| format!("Unable to connect to {addr}", addr = args.addr))?;
+        let Some(config) = tls_config(args)? else {
+            return Ok(Connection::Plain(stream));
+        };
+        let server_name = match &args.server_name {
+            Some(name) => ServerName::try_from(name.clone()).context("Invalid server name")?,
+            None => ServerName::IpAddress(args.addr.ip().into()),
+        };
+        let connection =
+            ClientConnection::new(config, server_name).context("Unable to start TLS")?;
+        let mut tls = StreamOwned::new(connection, stream);
+        // Complete handshake now so certificate errors are reported as such
+        tls.sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        tls.conn
+            .complete_io(&mut tls.sock)
+            .context("TLS handshake failed")?;
+        log::info!(
+            "Connected over {version:?}",
+            version = tls.conn.protocol_version()
+        );
+        Ok(Connection::Tls(Box::new(tls)))
+    }
+
+    /// Underlying TCP stream
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Connection::Plain(stream) => stream,
+            Connection::Tls(stream) => &stream.sock,
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// TLS settings verifying the server with a custom CA or a pinned certificate,
+/// presenting the client certificate if one is given.
+/// Returns `None` if neither is given and the connection is not encrypted.
+fn tls_config(args: &Args) -> Result<Option<Arc<ClientConfig>>> {
+    let provider = transport::crypto_provider();
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("Unable to select TLS versions")?;
+    let builder = if let Some(pin) = &args.pin {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificate::new(pin, provider)?))
+    } else if let Some(ca) = &args.ca {
+        let mut roots = RootCertStore::empty();
+        for cert in transport::load_certs(ca)? {
+            roots.add(cert).context("Invalid CA certificate")?;
+        }
+        builder.with_root_certificates(roots)
+    } else if args.cert.is_some() {
+        bail!("Client certificates require TLS, verify the server with --ca or --pin")
+    } else {
+        return Ok(None);
+    };
+    let config = match (&args.cert, &args.key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(transport::load_certs(cert)?, transport::load_key(key)?)
+            .context("Invalid client certificate or key")?,
+        _ => builder.with_no_client_auth(),
+    };
+    Ok(Some(Arc::new(config)))
+}
+
+/// Accepts only the server certificate with a given SHA-256 fingerprint, whoever issued it
+#[derive(Debug)]
+struct PinnedCertificate {
+    /// Fingerprint as uppercase hex without separators
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertificate {
+    fn new(fingerprint: &str, provider: Arc<CryptoProvider>) -> Result<Self> {
+        let fingerprint = normalize_fingerprint(fingerprint);
+        if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Pinned fingerprint must be a SHA-256 hash in hex")
+        }
+        Ok(Self {
+            fingerprint,
+            provider,
+        })
+    }
+}
+
+/// Fingerprint as uppercase hex without separators
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_uppercase()
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = transport::fingerprint(end_entity);
+        if normalize_fingerprint(&fingerprint) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "Server certificate fingerprint {fingerprint} does not match the pinned one"
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
 /// Command line arguments
 #[derive(Debug, Parser)]
 #[command(version, about, long_about=None)]
@@ -361,6 +1246,34 @@ struct Args {
     /// Address of the server
     #[arg(short, long)]
     addr: SocketAddr,
+    /// Server access token in hex, or token signed by the server administrators,
+    /// which needs TLS. Optional when the server accepts the client certificate.
+    #[arg(short, long, required_unless_present = "cert")]
+    token: Option<String>,
+    /// Nickname to use in the chat
+    #[arg(short, long)]
+    nick: Option<String>,
+    /// Connect over TLS, trusting server certificates issued by the CA in this PEM file
+    #[arg(long, conflicts_with = "pin")]
+    ca: Option<PathBuf>,
+    /// Connect over TLS, trusting only the server certificate with this SHA-256 fingerprint
+    #[arg(long)]
+    pin: Option<String>,
+    /// Name the server certificate must be valid for. Defaults to the server IP address.
+    #[arg(long)]
+    server_name: Option<String>,
+    /// PEM client certificate chain identifying you to the server, along with --key
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    /// PEM private key of the client certificate
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+    /// File holding your key for end-to-end encrypted messages, created if missing
+    #[arg(long, default_value = "identity.key")]
+    identity: PathBuf,
+    /// File remembering the encryption keys of peers, to warn when they change
+    #[arg(long, default_value = "known_keys.toml")]
+    known_keys: PathBuf,
 }
 
 fn main() -> Result<()> {
@@ -371,10 +1284,33 @@ fn main() -> Result<()> {
     // Parse arguments
     let args = Args::parse();
 
-    let stream = TcpStream::connect(args.addr)?;
-    stream.set_nonblocking(true)?;
+    let credential: Option<Credential> = args
+        .token
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .context("Invalid access token")?;
+
+    let mut stream = Connection::open(&args)?;
+    let capabilities = handshake(&mut stream)?;
+    authenticate(&mut stream, credential.as_ref(), args.nick)?;
+
+    // Publish key peers encrypt direct messages with
+    let encryption = if capabilities.contains(&Capability::Encryption) {
+        let key_pair = KeyPair::load_or_generate(&args.identity)?;
+        let known_keys = KnownKeys::load(&args.known_keys)?;
+        MessageToServer::PublishKey(key_pair.public)
+            .write_to(&mut stream)
+            .context("Unable to publish encryption key")?;
+        Some(Encryption::new(key_pair, known_keys))
+    } else {
+        None
+    };
+    stream.tcp().set_nonblocking(true)?;
 
-    if let Err(e) = ClientInterface::new(io::stdout(), stream)?.run() {
+    let mut client = ClientInterface::new(io::stdout(), stream, encryption)?;
+
+    if let Err(e) = client.run() {
         terminal::disable_raw_mode()?;
         log::error!("{e}");
         return Err(e);
@@ -382,3 +1318,29 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked(input: &str) -> String {
+        let mut prompt = Prompt::new(80);
+        prompt.push_str(input);
+        prompt.masked().into_owned()
+    }
+
+    #[test]
+    fn prompt_masks_passwords() {
+        assert_eq!(masked("/login alice hunter22"), "/login alice ********");
+        assert_eq!(masked("/register bob pass word"), "/register bob **** ****");
+        assert_eq!(masked("/passwd old  new"), "/passwd ***  ***");
+        assert_eq!(masked("/login"), "/login");
+    }
+
+    #[test]
+    fn prompt_shows_other_input() {
+        assert_eq!(masked("hello there"), "hello there");
+        assert_eq!(masked("/msg alice hunter22"), "/msg alice hunter22");
+        assert_eq!(masked("/loginx alice secret"), "/loginx alice secret");
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::{DateTime, Utc};
+use getrandom::getrandom;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+
+use crate::{
+    session::{validate_nickname, Role},
+    utils::{load_toml, save_toml},
+};
+
+/// Minimum password length in characters
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Maximum password length in characters
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+
+/// Size of password salts in bytes
+const SALT_LENGTH: usize = 16;
+
+/// Check password length
+pub fn validate_password(password: &str) -> Result<()> {
+    let len = password.chars().count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&len) {
+        bail!(
+            "Password must be between {MIN_PASSWORD_LENGTH} and {MAX_PASSWORD_LENGTH} characters long"
+        )
+    }
+    Ok(())
+}
+
+/// Hash password with Argon2 and a random salt, returning it in PHC string format
+fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0; SALT_LENGTH];
+    getrandom(&mut salt).map_err(|e| anyhow!("Unable to generate salt: {e}"))?;
+    let salt = SaltString::encode_b64(&salt).map_err(|e| anyhow!("Unable to encode salt: {e}"))?;
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Unable to hash password: {e}"))?;
+    Ok(hash.to_string())
+}
+
+/// Check password against hash in PHC string format
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Hash checked for unknown usernames so timing does not reveal which ones are registered
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash_password("dummy password").unwrap_or_default())
+}
+
+/// Registered user account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    /// Name the user logs in with, reserved as their nickname
+    pub username: String,
+    /// Argon2 hash of the password in PHC string format,
+    /// none for accounts of names proven by client certificate or signed token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+    /// Role granted on login
+    #[serde(default)]
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Layout of the accounts file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AccountFile {
+    #[serde(default)]
+    accounts: Vec<Account>,
+}
+
+/// Registered accounts, saved to disk on every change
+#[derive(Debug)]
+pub struct AccountStore {
+    /// File backing the store
+    path: PathBuf,
+    /// Accounts by lowercase username
+    accounts: HashMap<String, Account>,
+}
+
+impl AccountStore {
+    /// Load accounts from file, starting empty if it does not exist yet
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = load_toml(path, "accounts")?.unwrap_or_else(|| {
+            log::info!("Accounts file {} not found, starting empty", path.display());
+            AccountFile::default()
+        });
+        let store = Self {
+            path: path.to_owned(),
+            accounts: file
+                .accounts
+                .into_iter()
+                .map(|account| (account.username.to_lowercase(), account))
+                .collect(),
+        };
+        log::info!(
+            "Loaded {n} accounts from {path}",
+            n = store.accounts.len(),
+            path = path.display()
+        );
+        Ok(store)
+    }
+
+    /// Write accounts to their file
+    fn save(&self) -> Result<()> {
+        let mut accounts: Vec<Account> = self.accounts.values().cloned().collect();
+        accounts.sort_by(|a, b| a.username.cmp(&b.username));
+        save_toml(&self.path, "accounts", &AccountFile { accounts })
+    }
+
+    /// Account with username, ignoring case
+    pub fn get(&self, username: &str) -> Option<&Account> {
+        self.accounts.get(&username.to_lowercase())
+    }
+
+    /// Add new account, failing if the username is taken
+    fn insert(&mut self, account: Account) -> Result<()> {
+        let key = account.username.to_lowercase();
+        if self.accounts.contains_key(&key) {
+            bail!("Username {} is already registered", account.username)
+        }
+        self.accounts.insert(key.clone(), account);
+        self.save().inspect_err(|_| {
+            self.accounts.remove(&key);
+        })
+    }
+
+    /// Apply change to existing account and save it
+    fn update(&mut self, username: &str, change: impl FnOnce(&mut Account)) -> Result<()> {
+        let account = self
+            .accounts
+            .get_mut(&username.to_lowercase())
+            .ok_or(anyhow!("No account named {username}"))?;
+        change(account);
+        self.save()
+    }
+}
+
+/// Account store shared between the server and client tasks.
+/// Passwords are hashed outside the lock on blocking threads.
+#[derive(Debug, Clone)]
+pub struct Accounts(Arc<Mutex<AccountStore>>);
+
+impl Accounts {
+    /// Load accounts from file, starting empty if it does not exist yet
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self(Arc::new(Mutex::new(AccountStore::load(path)?))))
+    }
+
+    fn store(&self) -> MutexGuard<'_, AccountStore> {
+        self.0.lock().expect("Account store lock poisoned")
+    }
+
+    /// Whether username belongs to an account, ignoring case
+    pub fn is_registered(&self, username: &str) -> bool {
+        self.store().get(username).is_some()
+    }
+
+    /// Account with username, ignoring case
+    pub fn get(&self, username: &str) -> Option<Account> {
+        self.store().get(username).cloned()
+    }
+
+    /// Change role granted to account on login
+    pub fn set_role(&self, username: &str, role: Role) -> Result<()> {
+        self.store().update(username, |account| account.role = role)
+    }
+
+    /// Register new account with the role of a regular user
+    pub async fn register(&self, username: &str, password: String) -> Result<Account> {
+        validate_nickname(username).context("Invalid username")?;
+        validate_password(&password)?;
+        if self.is_registered(username) {
+            bail!("Username {username} is already registered")
+        }
+        let password_hash = task::spawn_blocking(move || hash_password(&password)).await??;
+        let account = Account {
+            username: username.to_owned(),
+            password_hash: Some(password_hash),
+            role: Role::default(),
+            created_at: Utc::now(),
+        };
+        self.store().insert(account.clone())?;
+        log::info!("Account {username} registered");
+        Ok(account)
+    }
+
+    /// Account of name proven by client certificate or signed token, created on first use.
+    /// Fails if the name is registered with a password, which an identity never takes over.
+    pub fn claim_identity(&self, username: &str) -> Result<Account> {
+        validate_nickname(username).context("Invalid username")?;
+        let mut store = self.store();
+        match store.get(username) {
+            Some(account) if account.password_hash.is_none() => return Ok(account.clone()),
+            Some(_) => bail!("Username {username} is registered with a password"),
+            None => {}
+        }
+        let account = Account {
+            username: username.to_owned(),
+            password_hash: None,
+            role: Role::default(),
+            created_at: Utc::now(),
+        };
+        store.insert(account.clone())?;
+        log::info!("Account {username} created for certificate or signed token identity");
+        Ok(account)
+    }
+
+    /// Account with username if password matches
+    pub async fn login(&self, username: &str, password: String) -> Result<Account> {
+        let account = self.store().get(username).cloned();
+        let password_hash = account
+            .as_ref()
+            .and_then(|account| account.password_hash.clone());
+        let valid = task::spawn_blocking(move || match password_hash {
+            Some(password_hash) => verify_password(&password, &password_hash),
+            None => {
+                verify_password(&password, dummy_hash());
+                false
+            }
+        })
+        .await?;
+        match account {
+            Some(account) if valid => Ok(account),
+            _ => bail!("Invalid username or password"),
+        }
+    }
+
+    /// Replace password of account if the old one matches
+    pub async fn change_password(
+        &self,
+        username: &str,
+        old_password: String,
+        new_password: String,
+    ) -> Result<()> {
+        validate_password(&new_password)?;
+        let password_hash = match self.store().get(username) {
+            Some(Account {
+                password_hash: Some(password_hash),
+                ..
+            }) => password_hash.clone(),
+            Some(_) => bail!("Account {username} logs in with a certificate or signed token"),
+            None => bail!("No account named {username}"),
+        };
+        let password_hash = task::spawn_blocking(move || {
+            if !verify_password(&old_password, &password_hash) {
+                bail!("Wrong password")
+            }
+            hash_password(&new_password)
+        })
+        .await??;
+        self.store().update(username, |account| {
+            account.password_hash = Some(password_hash)
+        })?;
+        log::info!("Account {username} changed password");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Accounts backed by a fresh file in the temporary directory
+    fn accounts(name: &str) -> (Accounts, PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "accounts-{name}-{pid}.toml",
+            pid = std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        (Accounts::load(&path).unwrap(), path)
+    }
+
+    #[tokio::test]
+    async fn identity_reserves_name() {
+        let (accounts, path) = accounts("identity");
+        accounts.claim_identity("alice").unwrap();
+        assert!(accounts.claim_identity("Alice").is_ok());
+        assert!(accounts
+            .register("alice", "password123".to_owned())
+            .await
+            .is_err());
+        assert!(accounts.login("alice", String::new()).await.is_err());
+        assert!(accounts
+            .change_password("alice", String::new(), "password123".to_owned())
+            .await
+            .is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn identity_never_takes_over_password_account() {
+        let (accounts, path) = accounts("password");
+        accounts
+            .register("bob", "password123".to_owned())
+            .await
+            .unwrap();
+        assert!(accounts.claim_identity("bob").is_err());
+        assert!(accounts
+            .login("bob", "password123".to_owned())
+            .await
+            .is_ok());
+        fs::remove_file(path).unwrap();
+    }
+}
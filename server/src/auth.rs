@@ -0,0 +1,390 @@
+use std::{env, fmt::Display, fs, str::FromStr};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use getrandom::getrandom;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    config::{AuthConfig, TokenSource},
+    session::Role,
+    signed_tokens::{Claims, TokenVerifier},
+    utils::write_private,
+};
+
+/// Size of authentication challenge nonces in bytes
+pub const NONCE_LENGTH: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Random nonce the client must sign with its token to authenticate
+pub fn generate_nonce() -> Result<[u8; NONCE_LENGTH]> {
+    let mut nonce = [0; NONCE_LENGTH];
+    getrandom(&mut nonce).map_err(|e| anyhow!("Unable to generate nonce: {e}"))?;
+    Ok(nonce)
+}
+
+/// Server Access Token, never sent over the wire.
+/// Clients prove they know it by answering a challenge with an HMAC-SHA256 of its nonce.
+#[derive(Debug, Clone)]
+pub struct Token(Vec<u8>);
+
+impl Token {
+    /// Generate new random access token of `length` bytes
+    fn generate(length: usize) -> Result<Token> {
+        let mut buffer = vec![0; length];
+        getrandom(&mut buffer).map_err(|e| anyhow!("Unable to generate random token: {e}"))?;
+        Ok(Token(buffer))
+    }
+
+    /// Response to authentication challenge
+    pub fn respond(&self, nonce: &[u8]) -> Vec<u8> {
+        self.mac()
+            .chain_update(nonce)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+
+    /// Check response to authentication challenge in constant time
+    fn verify(&self, nonce: &[u8], response: &[u8]) -> bool {
+        self.mac()
+            .chain_update(nonce)
+            .verify_slice(response)
+            .is_ok()
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.0).expect("HMAC accepts keys of any length")
+    }
+
+    /// Read token from its source
+    fn read(source: &TokenSource) -> Result<Self> {
+        let token = match source {
+            TokenSource::Token(token) => token.clone(),
+            TokenSource::File(path) => fs::read_to_string(path)
+                .with_context(|| format!("Unable to read token file {}", path.display()))?,
+            TokenSource::Env(name) => env::var(name)
+                .with_context(|| format!("Unable to read environment variable {name}"))?,
+        };
+        let token: Self = token.trim().parse()?;
+        if token.0.len() < AuthConfig::MIN_TOKEN_LENGTH {
+            bail!(
+                "Token must be at least {min} bytes long",
+                min = AuthConfig::MIN_TOKEN_LENGTH
+            )
+        }
+        Ok(token)
+    }
+}
+
+impl FromStr for Token {
+    type Err = anyhow::Error;
+
+    /// Attempts to parse access token from hex representation string
+    fn from_str(s: &str) -> Result<Self> {
+        if !s.is_ascii() {
+            bail!("Token string must be ASCII")
+        }
+        let str_len = s.len();
+        if str_len == 0 || !str_len.is_multiple_of(2) {
+            bail!("Invalid token string length: {str_len}")
+        }
+
+        (0..str_len)
+            .step_by(2)
+            .map(|k| u8::from_str_radix(&s[k..k + 2], 16).map_err(Into::into))
+            .collect::<Result<_>>()
+            .map(Token)
+    }
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{b:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Token accepted by the server
+#[derive(Debug, Clone)]
+pub struct AccessToken {
+    /// Name identifying the token in logs and commands
+    pub label: String,
+    pub token: Token,
+    /// Role granted by the token
+    pub role: Role,
+    /// Where the token was loaded from, `None` if generated at startup
+    source: Option<TokenSource>,
+    /// Time a token replaced by rotation stops working, `None` for current tokens
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl AccessToken {
+    /// Whether the token is still accepted
+    pub fn is_valid(&self) -> bool {
+        self.expires_at
+            .is_none_or(|expires_at| expires_at > Utc::now())
+    }
+
+    /// Whether the token is saved to a file, surviving restarts after rotation
+    pub fn is_persisted(&self) -> bool {
+        matches!(self.source, Some(TokenSource::File(_)))
+    }
+}
+
+/// Access tokens accepted by the server and the role each one grants
+#[derive(Debug, Clone)]
+pub struct AccessTokens {
+    tokens: Vec<AccessToken>,
+    /// Key verifying signed tokens, if they are accepted
+    signed: Option<TokenVerifier>,
+}
+
+impl AccessTokens {
+    /// Load configured tokens, generating random user and admin tokens if none are configured
+    /// and signed tokens are not enabled either
+    pub fn load(auth: &AuthConfig) -> Result<Self> {
+        Self::load_keeping(auth, None)
+    }
+
+    /// Load configured tokens again. Tokens rotated in memory are kept unless their source
+    /// changed, and retired tokens keep working until the end of their grace period.
+    pub fn reload(&self, auth: &AuthConfig) -> Result<Self> {
+        let mut access_tokens = Self::load_keeping(auth, Some(self))?;
+        access_tokens.tokens.extend(
+            self.tokens
+                .iter()
+                .filter(|access_token| access_token.expires_at.is_some() && access_token.is_valid())
+                .cloned(),
+        );
+        Ok(access_tokens)
+    }
+
+    /// Load configured tokens, keeping the current value of tokens of `previous`
+    /// with the same label and source unless it is a file rotation saves them to
+    fn load_keeping(auth: &AuthConfig, previous: Option<&AccessTokens>) -> Result<Self> {
+        let signed = auth
+            .signed_token_key
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .context("Invalid signed token key")?;
+        if signed.is_some() {
+            log::info!("Signed tokens accepted");
+        }
+        if auth.tokens.is_empty() && signed.is_none() {
+            return Self::generate(auth);
+        }
+        let tokens = auth
+            .tokens
+            .iter()
+            .map(|config| {
+                let kept = previous
+                    .filter(|_| !matches!(config.source, TokenSource::File(_)))
+                    .and_then(|previous| previous.current(&config.label))
+                    .filter(|current| current.source.as_ref() == Some(&config.source));
+                let token = match kept {
+                    Some(current) => current.token.clone(),
+                    None => Token::read(&config.source)
+                        .with_context(|| format!("Unable to load token {}", config.label))?,
+                };
+                Ok(AccessToken {
+                    label: config.label.clone(),
+                    token,
+                    role: config.role,
+                    source: Some(config.source.clone()),
+                    expires_at: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if !tokens.is_empty() {
+            log::info!("Loaded access tokens: {labels}", labels = labels(&tokens));
+        }
+        Ok(Self { tokens, signed })
+    }
+
+    /// Generate random user and admin tokens
+    fn generate(auth: &AuthConfig) -> Result<Self> {
+        let user = Token::generate(auth.token_length)?;
+        log::info!("Access token: {user}");
+        let admin = Token::generate(auth.token_length)?;
+        log::info!("Admin token: {admin}");
+        let generated = |label: &str, token, role| AccessToken {
+            label: label.to_owned(),
+            token,
+            role,
+            source: None,
+            expires_at: None,
+        };
+        Ok(Self {
+            tokens: vec![
+                generated("user", user, Role::User),
+                generated("admin", admin, Role::Admin),
+            ],
+            signed: None,
+        })
+    }
+
+    /// Valid access token whose HMAC of `nonce` is `response`
+    pub fn verify(&self, nonce: &[u8], response: &[u8]) -> Option<&AccessToken> {
+        // Check every token so timing does not reveal which one matched
+        self.tokens
+            .iter()
+            .filter(|access_token| access_token.is_valid())
+            .fold(None, |found, access_token| {
+                let matches = access_token.token.verify(nonce, response);
+                found.or(matches.then_some(access_token))
+            })
+    }
+
+    /// Claims of signed token, if signed tokens are accepted and the token is valid
+    pub fn verify_signed(&self, token: &str) -> Result<Claims> {
+        self.signed
+            .as_ref()
+            .context("Signed tokens are not accepted")?
+            .verify(token)
+    }
+
+    /// Current token with label
+    fn current(&self, label: &str) -> Option<&AccessToken> {
+        self.tokens
+            .iter()
+            .find(|access_token| access_token.label == label && access_token.expires_at.is_none())
+    }
+
+    /// Replace current token with label by a new random one of `length` bytes,
+    /// saving it to the token file if it was loaded from one.
+    /// The replaced token keeps working during `grace`.
+    pub fn rotate(&mut self, label: &str, length: usize, grace: TimeDelta) -> Result<AccessToken> {
+        self.tokens.retain(AccessToken::is_valid);
+        let index = self
+            .tokens
+            .iter()
+            .position(|access_token| {
+                access_token.label == label && access_token.expires_at.is_none()
+            })
+            .ok_or(anyhow!(
+                "No token labeled {label}. Tokens: {labels}",
+                labels = labels(&self.tokens)
+            ))?;
+
+        let expires_at = Utc::now()
+            .checked_add_signed(grace)
+            .context("Grace period too long")?;
+        let mut rotated = self.tokens[index].clone();
+        rotated.token = Token::generate(length)?;
+        if let Some(TokenSource::File(path)) = &rotated.source {
+            write_private(path, &format!("{token}\n", token = rotated.token))
+                .with_context(|| format!("Unable to save token file {}", path.display()))?;
+        }
+        self.tokens[index].expires_at = Some(expires_at);
+        self.tokens.push(rotated.clone());
+        Ok(rotated)
+    }
+}
+
+/// Comma separated labels of current tokens
+fn labels(tokens: &[AccessToken]) -> String {
+    tokens
+        .iter()
+        .filter(|access_token| access_token.expires_at.is_none())
+        .map(|access_token| access_token.label.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TokenConfig;
+
+    fn config(token: &str) -> AuthConfig {
+        AuthConfig {
+            tokens: vec![TokenConfig {
+                label: "members".to_owned(),
+                role: Role::User,
+                source: TokenSource::Token(token.to_owned()),
+            }],
+            ..AuthConfig::default()
+        }
+    }
+
+    fn accepts(access_tokens: &AccessTokens, token: &Token) -> bool {
+        let nonce = generate_nonce().unwrap();
+        access_tokens
+            .verify(&nonce, &token.respond(&nonce))
+            .is_some()
+    }
+
+    #[test]
+    fn challenge_response() {
+        let token: Token = "00112233445566778899AABBCCDDEEFF".parse().unwrap();
+        let other: Token = "FFEEDDCCBBAA99887766554433221100".parse().unwrap();
+        let nonce = generate_nonce().unwrap();
+        assert!(token.verify(&nonce, &token.respond(&nonce)));
+        assert!(!token.verify(&nonce, &other.respond(&nonce)));
+        assert!(!token.verify(&generate_nonce().unwrap(), &token.respond(&nonce)));
+    }
+
+    #[test]
+    fn reload_keeps_rotated_token() {
+        let original: Token = "00112233445566778899AABBCCDDEEFF".parse().unwrap();
+        let auth = config(&original.to_string());
+        let mut access_tokens = AccessTokens::load(&auth).unwrap();
+        let rotated = access_tokens
+            .rotate("members", 16, TimeDelta::hours(1))
+            .unwrap();
+
+        let reloaded = access_tokens.reload(&auth).unwrap();
+        assert!(accepts(&reloaded, &rotated.token));
+        assert!(accepts(&reloaded, &original));
+        let current = reloaded.current("members").unwrap();
+        assert_eq!(current.token.0, rotated.token.0);
+        assert_eq!(current.expires_at, None);
+    }
+
+    #[test]
+    fn reload_reads_changed_source() {
+        let mut access_tokens =
+            AccessTokens::load(&config("00112233445566778899AABBCCDDEEFF")).unwrap();
+        access_tokens
+            .rotate("members", 16, TimeDelta::hours(1))
+            .unwrap();
+        let replacement: Token = "FFEEDDCCBBAA99887766554433221100".parse().unwrap();
+
+        let reloaded = access_tokens
+            .reload(&config(&replacement.to_string()))
+            .unwrap();
+        let current = reloaded.current("members").unwrap();
+        assert_eq!(current.token.0, replacement.0);
+    }
+
+    #[test]
+    fn retired_token_expires() {
+        let original: Token = "00112233445566778899AABBCCDDEEFF".parse().unwrap();
+        let mut access_tokens = AccessTokens::load(&config(&original.to_string())).unwrap();
+        access_tokens
+            .rotate("members", 16, TimeDelta::zero())
+            .unwrap();
+        assert!(!accepts(&access_tokens, &original));
+        assert!(!accepts(
+            &access_tokens
+                .reload(&config(&original.to_string()))
+                .unwrap(),
+            &original
+        ));
+    }
+
+    #[test]
+    fn rotate_rejects_overflowing_grace() {
+        let original: Token = "00112233445566778899AABBCCDDEEFF".parse().unwrap();
+        let mut access_tokens = AccessTokens::load(&config(&original.to_string())).unwrap();
+        assert!(access_tokens.rotate("members", 16, TimeDelta::MAX).is_err());
+        let current = access_tokens.current("members").unwrap();
+        assert_eq!(current.token.0, original.0);
+    }
+}
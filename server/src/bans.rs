@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::EscalationPolicy,
+    requests::BanReason,
+    utils::{load_toml, save_toml},
+};
+
+/// Parse CIDR range such as `10.0.0.0/8` or `2001:db8::/32`. Plain addresses ban a single host.
+pub fn parse_range(s: &str) -> Option<IpNet> {
+    s.parse::<IpNet>()
+        .map(|range| range.trunc())
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+}
+
+/// Ban of a range of IP addresses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    /// Banned addresses. Single addresses are stored as `/32` or `/128` ranges.
+    pub range: IpNet,
+    /// Username of the banned account, refused login from any address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Time the ban expires, or `None` for permanent bans
+    pub expires_at: Option<DateTime<Utc>>,
+    pub reason: BanReason,
+    /// Name of the moderator who issued the ban, or `server` for automatic bans
+    pub issued_by: String,
+    pub issued_at: DateTime<Utc>,
+}
+
+impl Ban {
+    /// Seconds left until the ban expires, or `None` for permanent bans
+    pub fn remaining_secs(&self) -> Option<i64> {
+        self.expires_at
+            .map(|expires_at| expires_at.signed_duration_since(Utc::now()).num_seconds())
+    }
+
+    /// Whether the ban is still in effect
+    pub fn is_active(&self) -> bool {
+        self.remaining_secs().is_none_or(|secs| secs > 0)
+    }
+}
+
+/// Offense history of an IP address
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Offenses {
+    pub ip: IpAddr,
+    /// Number of bans since the history was last forgiven
+    pub count: u32,
+    pub last_offense: DateTime<Utc>,
+}
+
+/// Layout of the ban list file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BanFile {
+    #[serde(default)]
+    bans: Vec<Ban>,
+    #[serde(default)]
+    offenses: Vec<Offenses>,
+}
+
+/// Bans in effect and offense history, saved to disk on every change so they survive restarts
+#[derive(Debug)]
+pub struct BanList {
+    /// File backing the list
+    path: PathBuf,
+    bans: Vec<Ban>,
+    offenses: HashMap<IpAddr, Offenses>,
+    escalation: EscalationPolicy,
+}
+
+impl BanList {
+    /// Load ban list from file, starting empty if it does not exist yet
+    pub fn load(path: impl AsRef<Path>, escalation: EscalationPolicy) -> Result<Self> {
+        let path = path.as_ref();
+        let file = load_toml(path, "ban list")?.unwrap_or_else(|| {
+            log::info!("Ban list file {} not found, starting empty", path.display());
+            BanFile::default()
+        });
+        let mut ban_list = Self {
+            path: path.to_owned(),
+            bans: file.bans,
+            offenses: file
+                .offenses
+                .into_iter()
+                .map(|offenses| (offenses.ip, offenses))
+                .collect(),
+            escalation,
+        };
+        ban_list.prune();
+        log::info!(
+            "Loaded {n} bans from {path}",
+            n = ban_list.bans.len(),
+            path = path.display()
+        );
+        Ok(ban_list)
+    }
+
+    /// Change how automatic bans escalate from now on
+    pub fn set_escalation(&mut self, escalation: EscalationPolicy) {
+        self.escalation = escalation;
+    }
+
+    /// Write ban list to its file
+    fn save(&self) {
+        let file = BanFile {
+            bans: self.bans.clone(),
+            offenses: self.offenses.values().cloned().collect(),
+        };
+        if let Err(e) = save_toml(&self.path, "ban list", &file) {
+            log::error!("{e:#}");
+        }
+    }
+
+    /// Active ban covering IP address
+    pub fn find(&self, ip: IpAddr) -> Option<&Ban> {
+        self.bans
+            .iter()
+            .find(|ban| ban.is_active() && ban.range.contains(&ip))
+    }
+
+    /// Active ban of account
+    pub fn find_account(&self, username: &str) -> Option<&Ban> {
+        self.bans.iter().find(|ban| {
+            ban.is_active()
+                && ban
+                    .account
+                    .as_ref()
+                    .is_some_and(|account| account.to_lowercase() == username.to_lowercase())
+        })
+    }
+
+    /// Add ban, replacing any ban of the same range and account
+    pub fn insert(&mut self, ban: Ban) {
+        let account = ban.account.as_deref().map(str::to_lowercase);
+        self.bans.retain(|other| {
+            other.range != ban.range || other.account.as_deref().map(str::to_lowercase) != account
+        });
+        self.bans.push(ban);
+        self.save();
+    }
+
+    /// Lift bans of exactly this range, including those of accounts banned along with it
+    pub fn remove(&mut self, range: &IpNet) -> Vec<Ban> {
+        let (removed, kept) = self.bans.drain(..).partition(|ban| ban.range == *range);
+        self.bans = kept;
+        if !removed.is_empty() {
+            self.save();
+        }
+        removed
+    }
+
+    /// Record new offense of IP address, forgiving history older than the decay window.
+    /// Returns the escalated ban duration for it.
+    pub fn record_offense(&mut self, ip: IpAddr) -> TimeDelta {
+        let now = Utc::now();
+        let decay = self.escalation.decay;
+        let offenses = self.offenses.entry(ip).or_insert(Offenses {
+            ip,
+            count: 0,
+            last_offense: now,
+        });
+        if now.signed_duration_since(offenses.last_offense) > decay {
+            offenses.count = 0;
+        }
+        offenses.count = offenses.count.saturating_add(1);
+        offenses.last_offense = now;
+        let count = offenses.count;
+        self.save();
+        let duration = self.escalation.ban_duration(count);
+        log::info!(
+            "IP {ip} offense number {count}, ban duration {secs} seconds",
+            secs = duration.num_seconds()
+        );
+        duration
+    }
+
+    /// Drop expired bans and forgiven offense history
+    pub fn prune(&mut self) {
+        let (bans, offenses) = (self.bans.len(), self.offenses.len());
+        self.bans.retain(|ban| {
+            let active = ban.is_active();
+            if !active {
+                log::info!("Ban of {range} expired", range = ban.range);
+            }
+            active
+        });
+        let now = Utc::now();
+        let decay = self.escalation.decay;
+        self.offenses.retain(|ip, offenses| {
+            let remembered = now.signed_duration_since(offenses.last_offense) <= decay;
+            if !remembered {
+                log::info!("Offense history of IP {ip} forgiven");
+            }
+            remembered
+        });
+        if self.bans.len() != bans || self.offenses.len() != offenses {
+            self.save();
+        }
+    }
+
+    /// Bans in effect
+    pub fn iter(&self) -> impl Iterator<Item = &Ban> {
+        self.bans.iter().filter(|ban| ban.is_active())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn ban(range: &str, account: Option<&str>, secs: i64) -> Ban {
+        let now = Utc::now();
+        Ban {
+            range: parse_range(range).unwrap(),
+            account: account.map(str::to_owned),
+            expires_at: Some(now + TimeDelta::seconds(secs)),
+            reason: BanReason::Other("test".to_owned()),
+            issued_by: "tester".to_owned(),
+            issued_at: now,
+        }
+    }
+
+    /// Ban list backed by a fresh file in the temporary directory
+    fn ban_list(name: &str) -> (BanList, PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("bans-{name}-{pid}.toml", pid = std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ban_list = BanList::load(&path, EscalationPolicy::default()).unwrap();
+        (ban_list, path)
+    }
+
+    #[test]
+    fn address_ban_keeps_account_ban() {
+        let (mut bans, path) = ban_list("account");
+        bans.insert(ban("192.0.2.1", Some("Alice"), 3600));
+        bans.insert(ban("192.0.2.1", None, 60));
+        assert_eq!(bans.iter().count(), 2);
+        assert!(bans.find_account("alice").is_some());
+
+        // Same range and account replaces the earlier ban
+        bans.insert(ban("192.0.2.1", Some("alice"), 7200));
+        assert_eq!(bans.iter().count(), 2);
+        assert!(
+            bans.find_account("ALICE")
+                .unwrap()
+                .remaining_secs()
+                .unwrap()
+                > 3600
+        );
+
+        // Bans survive reloading the file
+        let reloaded = BanList::load(&path, EscalationPolicy::default()).unwrap();
+        assert_eq!(reloaded.iter().count(), 2);
+
+        assert_eq!(bans.remove(&parse_range("192.0.2.1").unwrap()).len(), 2);
+        assert!(bans.find_account("alice").is_none());
+        assert!(bans.find("192.0.2.1".parse().unwrap()).is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn find_matches_range_and_skips_expired() {
+        let (mut bans, path) = ban_list("range");
+        bans.insert(ban("10.0.0.0/8", None, 60));
+        bans.insert(ban("192.0.2.1", None, -1));
+        assert!(bans.find("10.20.30.40".parse().unwrap()).is_some());
+        assert!(bans.find("11.0.0.1".parse().unwrap()).is_none());
+        assert!(bans.find("192.0.2.1".parse().unwrap()).is_none());
+        bans.prune();
+        assert_eq!(bans.iter().count(), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn parse_range_accepts_addresses_and_cidr() {
+        assert_eq!(
+            parse_range("192.0.2.7"),
+            Some("192.0.2.7/32".parse().unwrap())
+        );
+        assert_eq!(
+            parse_range("2001:db8::1"),
+            Some("2001:db8::1/128".parse().unwrap())
+        );
+        assert_eq!(
+            parse_range("10.1.2.3/8"),
+            Some("10.0.0.0/8".parse().unwrap())
+        );
+        assert_eq!(
+            parse_range("2001:db8:ffff::/32"),
+            Some("2001:db8::/32".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_range_rejects_garbage() {
+        assert_eq!(parse_range(""), None);
+        assert_eq!(parse_range("alice"), None);
+        assert_eq!(parse_range("10.0.0.0/33"), None);
+        assert_eq!(parse_range("300.0.0.1"), None);
+    }
+}
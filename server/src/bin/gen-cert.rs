@@ -0,0 +1,54 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+
+use server::{transport, utils::write_private};
+
+/// Generate a self-signed TLS certificate and private key for the server
+#[derive(Debug, Parser)]
+#[command(version, about, long_about=None)]
+struct Args {
+    /// File the PEM certificate is written to
+    #[arg(long, default_value = "server.crt")]
+    cert: PathBuf,
+    /// File the PEM private key is written to
+    #[arg(long, default_value = "server.key")]
+    key: PathBuf,
+    /// Host name or address the certificate is valid for. May be given multiple times.
+    #[arg(short, long, default_values = ["localhost", "127.0.0.1"])]
+    name: Vec<String>,
+    /// Overwrite existing files
+    #[arg(long)]
+    force: bool,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    if !args.force {
+        for path in [&args.cert, &args.key] {
+            if path.exists() {
+                bail!(
+                    "{} already exists, use --force to overwrite it",
+                    path.display()
+                )
+            }
+        }
+    }
+
+    let self_signed = transport::generate_self_signed(args.name.clone())?;
+    fs::write(&args.cert, &self_signed.cert)
+        .with_context(|| format!("Unable to write certificate to {}", args.cert.display()))?;
+    write_private(&args.key, &self_signed.key)
+        .with_context(|| format!("Unable to write private key to {}", args.key.display()))?;
+
+    println!(
+        "Certificate for {names} written to {cert}, private key to {key}",
+        names = args.name.join(", "),
+        cert = args.cert.display(),
+        key = args.key.display()
+    );
+    println!("SHA-256 fingerprint: {}", self_signed.fingerprint);
+    Ok(())
+}
@@ -1,59 +1,290 @@
-use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
-    sync::mpsc,
-    thread,
-};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 
 use anyhow::{Context, Result};
+use clap::Parser;
+use log::LevelFilter;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
+    sync::{
+        mpsc::{self, UnboundedSender},
+        watch, Semaphore,
+    },
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+
+use server::{
+    accounts::Accounts,
+    auth::AccessTokens,
+    client::Client,
+    config::{Config, ConfigSource, TlsConfig},
+    lockout::AuthFailures,
+    requests::ClientRequest,
+    server::Server,
+    transport::{self, Transport},
+};
 
-use server::{client::Client, requests::ClientRequest, server::Server};
+/// Command line arguments. Values given here override the configuration file.
+#[derive(Debug, Parser)]
+#[command(version, about, long_about=None)]
+struct Args {
+    /// Configuration file in TOML format
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Address to accept connections on. May be given multiple times.
+    #[arg(short, long)]
+    listen: Vec<SocketAddr>,
+    /// PEM certificate chain enabling TLS, along with --tls-key
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the TLS certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// PEM file with the CA certificates accepted client certificates are signed by
+    #[arg(long)]
+    client_ca: Option<PathBuf>,
+    /// Refuse clients without a certificate signed by the client CA
+    #[arg(long)]
+    require_client_cert: bool,
+    /// File bans are persisted to
+    #[arg(long)]
+    ban_file: Option<PathBuf>,
+    /// File registered user accounts are persisted to
+    #[arg(long)]
+    accounts_file: Option<PathBuf>,
+    /// Length of generated access tokens in bytes
+    #[arg(long)]
+    token_length: Option<usize>,
+    /// Maximum number of concurrent connections yet to authenticate
+    #[arg(long)]
+    max_unauthenticated: Option<usize>,
+    /// Most verbose level logged
+    #[arg(long)]
+    log_level: Option<LevelFilter>,
+    /// Disable colored log output
+    #[arg(long)]
+    no_color: bool,
+}
 
-// TODO: Better async. Look `tokio` lib
+impl Args {
+    /// Load configuration file, if any, and apply overrides
+    fn config(&self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if !self.listen.is_empty() {
+            config.listeners = self.listen.clone();
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            // Keep client certificate settings of the configuration file
+            let tls = config.tls.get_or_insert_with(|| TlsConfig {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: None,
+                require_client_cert: false,
+            });
+            tls.cert = cert.clone();
+            tls.key = key.clone();
+        }
+        if self.client_ca.is_some() || self.require_client_cert {
+            let tls = config
+                .tls
+                .as_mut()
+                .context("Client certificates require TLS to be enabled")?;
+            if let Some(client_ca) = &self.client_ca {
+                tls.client_ca = Some(client_ca.clone());
+            }
+            tls.require_client_cert |= self.require_client_cert;
+        }
+        if let Some(ban_file) = &self.ban_file {
+            config.bans.file = ban_file.clone();
+        }
+        if let Some(accounts_file) = &self.accounts_file {
+            config.accounts.file = accounts_file.clone();
+        }
+        if let Some(token_length) = self.token_length {
+            config.auth.token_length = token_length;
+        }
+        if let Some(max_unauthenticated) = self.max_unauthenticated {
+            config.limits.max_unauthenticated = max_unauthenticated;
+        }
+        if let Some(log_level) = self.log_level {
+            config.logging.level = log_level;
+        }
+        if self.no_color {
+            config.logging.colors = false;
+        }
+        Ok(config)
+    }
+}
 
-const PORT: u16 = 6969;
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Parse arguments and configuration, reloaded with the same arguments
+    let args = Args::parse();
+    let config_source = ConfigSource::new(move || args.config());
+    let config = config_source.load()?;
 
-fn main() -> Result<()> {
     simple_logger::SimpleLogger::new()
+        .with_level(config.logging.level)
         .env()
-        .with_colors(true)
+        .with_colors(config.logging.colors)
         .with_local_timestamps()
         .init()
         .context("Unable to initialize logger")?;
 
-    // Bind TCP listener to address
-    let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), PORT);
-    let tcp_listener = TcpListener::bind(server_addr).context("Unable to bind TCP listener")?;
-    log::info!("Listening to address {server_addr}");
+    // Bind TCP listeners to addresses
+    let mut listeners = Vec::with_capacity(config.listeners.len());
+    for addr in &config.listeners {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Unable to bind TCP listener to {addr}"))?;
+        log::info!("Listening to address {addr}");
+        listeners.push(listener);
+    }
+
+    // Failed authentication attempts by address and passwords by account, kept across connections
+    let auth_failures = AuthFailures::new();
+    let login_failures = AuthFailures::new();
+
+    // Connections yet to authenticate
+    let unauthenticated = Arc::new(Semaphore::new(config.limits.max_unauthenticated));
 
     // Requests channel
-    let (request_sender, request_receiver) = mpsc::channel::<ClientRequest>();
+    let (request_sender, request_receiver) = mpsc::unbounded_channel::<ClientRequest>();
+
+    // Create server
+    let server = Server::new(request_receiver, config, config_source)
+        .context("Unable to create new Server")?;
+
+    // Reload configuration on SIGHUP
+    let reload = server.reload_trigger();
+    let mut hangups = signal(SignalKind::hangup()).context("Unable to listen to SIGHUP")?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            log::info!("SIGHUP received, reloading configuration");
+            if reload.send(()).is_err() {
+                break;
+            }
+        }
+    });
 
-    // Launch server
-    let server = Server::new(request_receiver).context("Unable to create new Server")?;
-    let access_token = server.access_token();
-    let _server_handle = thread::spawn(move || server.run());
+    // Encrypt connections if configured
+    let tls = server
+        .config()
+        .borrow()
+        .tls
+        .as_ref()
+        .map(transport::acceptor)
+        .transpose()
+        .context("Unable to set up TLS")?;
 
     // Listen to incoming TCP connections
-    for incoming_stream in tcp_listener.incoming() {
-        // Handle TCP connections
-        match incoming_stream {
-            Err(e) => log::error!("Could not handle incoming TCP connection: {e}"),
-            Ok(stream) => {
-                // Spawn client thread
-                match Client::new(stream, request_sender.clone()) {
-                    Err(e) => log::error!("Unable to create new Client: {e}"),
-                    Ok(mut client) => {
-                        let _ = thread::spawn(move || {
-                            if let Err(e) = client.run(access_token) {
-                                log::error!("Error in {client} thread: {e}",);
-                                let _ = client.shutdown();
-                            }
-                        });
+    let context = ClientContext {
+        request_sender,
+        config: server.config(),
+        tokens: server.access_tokens(),
+        accounts: server.accounts(),
+        auth_failures,
+        login_failures,
+        unauthenticated,
+        tls,
+    };
+    for listener in listeners {
+        tokio::spawn(accept_connections(listener, context.clone()));
+    }
+    drop(context);
+
+    // Run server
+    server.run().await
+}
+
+/// State handed to every client task
+#[derive(Clone)]
+struct ClientContext {
+    request_sender: UnboundedSender<ClientRequest>,
+    config: watch::Receiver<Arc<Config>>,
+    tokens: watch::Receiver<Arc<AccessTokens>>,
+    accounts: Accounts,
+    auth_failures: AuthFailures,
+    /// Failed password attempts by lowercase username
+    login_failures: AuthFailures<String>,
+    /// Connections yet to authenticate
+    unauthenticated: Arc<Semaphore>,
+    /// Acceptor securing connections, if TLS is enabled
+    tls: Option<TlsAcceptor>,
+}
+
+impl ClientContext {
+    /// Slow down addresses guessing credentials, then secure connection if TLS is enabled
+    /// and run client task. Delayed connections hold no `unauthenticated` permit,
+    /// which is held from the end of the delay until the client is authenticated.
+    async fn serve(self, stream: TcpStream, addr: SocketAddr) {
+        let policy = self.config.borrow().lockout;
+        let delay = self.auth_failures.delay(&addr.ip(), &policy);
+        if !delay.is_zero() {
+            log::debug!(
+                "Client {addr} delayed {ms} ms after failed attempts",
+                ms = delay.as_millis()
+            );
+            time::sleep(delay).await;
+        }
+
+        // Limit connections yet to authenticate
+        let Ok(permit) = self.unauthenticated.clone().try_acquire_owned() else {
+            log::warn!("Too many unauthenticated connections, refusing connection from {addr}");
+            return;
+        };
+
+        let stream = match &self.tls {
+            None => Transport::Plain(stream),
+            Some(acceptor) => {
+                let handshake_timeout = self.config.borrow().limits.handshake_timeout;
+                match time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => Transport::Tls(Box::new(stream)),
+                    Ok(Err(e)) => {
+                        log::warn!("TLS handshake with {addr} failed: {e}");
+                        return;
+                    }
+                    Err(_) => {
+                        log::warn!("TLS handshake with {addr} timed out");
+                        return;
                     }
                 }
             }
+        };
+        match Client::new(
+            stream,
+            self.request_sender,
+            self.config,
+            self.accounts,
+            self.auth_failures,
+            self.login_failures,
+        ) {
+            Err(e) => log::error!("Unable to create new Client: {e}"),
+            Ok(mut client) => {
+                let tokens = self.tokens.borrow().clone();
+                if let Err(e) = client.run(tokens, permit).await {
+                    log::error!("Error in {client} task: {e}",);
+                    client.shutdown();
+                }
+            }
         }
     }
+}
 
-    Ok(())
+/// Spawn client task for every connection accepted by listener
+async fn accept_connections(listener: TcpListener, context: ClientContext) {
+    loop {
+        // Handle TCP connections
+        match listener.accept().await {
+            Err(e) => log::error!("Could not handle incoming TCP connection: {e}"),
+            Ok((stream, addr)) => {
+                // Spawn client task
+                tokio::spawn(context.clone().serve(stream, addr));
+            }
+        }
+    }
 }
@@ -0,0 +1,90 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+
+use server::{
+    session::Role,
+    signed_tokens::{Claims, TokenSigner},
+    utils::parse_duration,
+};
+
+/// Manage signed login tokens, verified by the server without a database
+#[derive(Debug, Parser)]
+#[command(version, about, long_about=None)]
+struct Args {
+    /// File holding the signing key in base64
+    #[arg(short, long, default_value = "signing.key", global = true)]
+    key: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Generate signing key and print the public key to configure on the server
+    Keygen {
+        /// Overwrite existing key, invalidating every token signed with it
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the public key of the signing key
+    PublicKey,
+    /// Issue token to a user
+    Issue {
+        /// Name the user logs in as
+        #[arg(short, long)]
+        username: String,
+        /// Role granted: user, moderator or admin
+        #[arg(short, long, default_value = "user")]
+        role: Role,
+        /// Time the token is valid for, such as 90s, 12h or 30d
+        #[arg(short, long, default_value = "30d")]
+        expires: String,
+    },
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    match args.command {
+        Command::Keygen { force } => {
+            if args.key.exists() && !force {
+                bail!(
+                    "{} already exists, use --force to overwrite it",
+                    args.key.display()
+                )
+            }
+            let signer = TokenSigner::generate()?;
+            signer.save(&args.key)?;
+            println!("Signing key written to {}", args.key.display());
+            println!("Public key for the server configuration:");
+            println!("signed_token_key = \"{}\"", signer.public_key());
+        }
+        Command::PublicKey => {
+            println!("{}", TokenSigner::load(&args.key)?.public_key());
+        }
+        Command::Issue {
+            username,
+            role,
+            expires,
+        } => {
+            let signer = TokenSigner::load(&args.key)?;
+            let validity = parse_duration(&expires).context("Invalid expiry")?;
+            let issued_at = Utc::now();
+            let expires_at = issued_at
+                .checked_add_signed(validity)
+                .context("Expiry too far in the future")?;
+            let token = signer.issue(&Claims {
+                username,
+                role,
+                issued_at,
+                expires_at,
+            })?;
+            eprintln!("Token valid until {expires_at}");
+            println!("{token}");
+        }
+    }
+    Ok(())
+}
@@ -1,54 +1,76 @@
-use core::str;
-use std::{
-    fmt::Display,
-    io::Read,
-    net::{SocketAddr, TcpStream},
-    sync::{mpsc::Sender, Arc},
-};
+use std::{fmt::Display, future::Future, net::SocketAddr, sync::Arc};
 
 use anyhow::{bail, Context, Result};
-use chrono::{DateTime, TimeDelta, Utc};
 use log::debug;
+use tokio::{
+    io::{self, ReadHalf},
+    sync::{mpsc::UnboundedSender, watch, OwnedSemaphorePermit},
+    time,
+};
 
 use crate::{
-    messages::{MessageAuthor, MessageToClient, ServerMessage},
+    accounts::{validate_password, Account, Accounts},
+    auth::{self, AccessTokens},
+    config::{Config, ConnectionLimits, Consequence},
+    framing::{self, FrameError},
+    lockout::{AuthFailures, Failure},
+    messages::{
+        Capability, Hello, HelloReply, MessageAuthor, MessageToClient, MessageToServer,
+        ServerMessage, WireMessage, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    outbound::{self, Enqueued, Outbound},
+    ratelimit::{RateLimiter, Traffic},
     requests::{BanReason, ClientRequest, Request},
-    server::Token,
+    session::{validate_nickname, Role},
+    transport::Transport,
 };
 
-// TODO: Receive message struct from remote client
 // TODO: Let client know when server is offline
-// TODO: Is there a way to send message from server thread to client thread?
+// TODO: Is there a way to send message from server task to client task?
 // TODO: Send confirmations to client
 
-const BUFFER_SIZE: usize = 64 * 1024; // 64kb
-const MESSAGE_COOLDOWN_TIME: TimeDelta = TimeDelta::milliseconds(300);
-const MAX_STRIKE_COUNT: u32 = 5;
+/// Capabilities supported by the server
+const SUPPORTED_CAPABILITIES: &[Capability] = &[Capability::Rooms, Capability::Encryption];
 
-/// Handle incoming data
-fn parse_text(bytes: &[u8]) -> Result<String> {
+/// Sanitize incoming text
+fn sanitize_text(text: &str) -> String {
     // Filter out escape codes
-    let bytes_safe: Vec<u8> = bytes.iter().copied().filter(|c| *c >= 32).collect();
-    // Read UTF-8
-    let text = str::from_utf8(&bytes_safe).context("Data is not valid UTF-8")?;
-    Ok(text.to_owned())
+    text.chars().filter(|c| !c.is_control()).collect()
 }
 
-/// Client thread
-#[derive(Debug, Clone)]
+/// Client task
+#[derive(Debug)]
 pub struct Client {
     /// Remote address
     addr: SocketAddr,
-    /// Remote stream
-    stream: Arc<TcpStream>,
+    /// Read half of the remote stream
+    reader: ReadHalf<Transport>,
+    /// Queue of messages to be written to the remote stream, shared with the server
+    outbound: Arc<Outbound>,
     /// Channel to send request to server
-    sender: Sender<ClientRequest>,
-    /// Buffer to read incoming data into
-    buffer: [u8; BUFFER_SIZE],
-    /// Time of the last message sent by the client
-    last_message_time: DateTime<Utc>,
-    /// Number of strikes of the client to avoid spamming
-    strike_count: u32,
+    sender: UnboundedSender<ClientRequest>,
+    /// Server configuration, updated when reloaded
+    config: watch::Receiver<Arc<Config>>,
+    /// Connection timeouts and limits
+    limits: ConnectionLimits,
+    /// Limits incoming traffic
+    rate_limiter: RateLimiter,
+    /// Capabilities negotiated during handshake
+    capabilities: Vec<Capability>,
+    /// Registered accounts
+    accounts: Accounts,
+    /// Username of the account logged into
+    account: Option<String>,
+    /// Failed authentication attempts of every address
+    auth_failures: AuthFailures,
+    /// Failed password attempts on every account, by lowercase username
+    login_failures: AuthFailures<String>,
+    /// Common name of the verified client certificate, if any
+    certificate: Option<String>,
+    /// Account of the name proven by client certificate or signed token, logged into once connected
+    identity: Option<Account>,
+    /// Whether the connection is encrypted with TLS
+    tls: bool,
 }
 
 impl Display for Client {
@@ -59,18 +81,52 @@ impl Display for Client {
 
 impl Client {
     /// Construct new Client
-    pub fn new(stream: TcpStream, sender: Sender<ClientRequest>) -> Result<Self> {
+    pub fn new(
+        stream: Transport,
+        sender: UnboundedSender<ClientRequest>,
+        mut config: watch::Receiver<Arc<Config>>,
+        accounts: Accounts,
+        auth_failures: AuthFailures,
+        login_failures: AuthFailures<String>,
+    ) -> Result<Self> {
+        let limits = config.borrow_and_update().limits;
         let addr = stream
             .peer_addr()
             .context("Unable to identify client address")?;
+        let certificate = stream
+            .client_identity()
+            .context("Unable to identify client certificate")?;
+        let tls = stream.is_tls();
+        let (reader, writer) = io::split(stream);
+
+        // Spawn writer task draining the outbound queue
+        let outbound = Arc::new(Outbound::new(
+            limits.outbound_capacity,
+            limits.overflow_policy,
+        ));
+        tokio::spawn(outbound::write_loop(
+            outbound.clone(),
+            writer,
+            addr,
+            limits.write_timeout,
+        ));
 
         Ok(Self {
             addr,
-            stream: Arc::new(stream),
+            reader,
+            outbound,
             sender,
-            buffer: [0; BUFFER_SIZE],
-            last_message_time: Utc::now(),
-            strike_count: 0,
+            config,
+            limits,
+            rate_limiter: RateLimiter::new(&limits.rate_limits),
+            capabilities: Vec::new(),
+            accounts,
+            account: None,
+            auth_failures,
+            login_failures,
+            certificate,
+            identity: None,
+            tls,
         })
     }
 
@@ -79,67 +135,276 @@ impl Client {
         self.addr
     }
 
+    /// Get capabilities negotiated with the remote client
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    /// Queue message to be written to remote stream
+    fn write_message(&self, message: &impl WireMessage) -> Result<()> {
+        let frame = framing::encode_frame(&message.encode()?)?;
+        match self.outbound.push(frame.into()) {
+            Enqueued::Closed | Enqueued::Disconnected => bail!("Connection closed"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read next message from remote stream. Returns `None` on EOF.
+    async fn read_message<M: WireMessage + std::fmt::Debug>(&mut self) -> Result<Option<M>> {
+        Ok(self.read_sized_message().await?.map(|(message, _)| message))
+    }
+
+    /// Read message from remote stream along with its size in bytes
+    async fn read_sized_message<M: WireMessage + std::fmt::Debug>(
+        &mut self,
+    ) -> Result<Option<(M, usize)>> {
+        log::trace!("{self} attempting to read from stream");
+        let message = match framing::read_frame_async(&mut self.reader).await? {
+            None => None,
+            Some(payload) => Some((M::decode(&payload)?, payload.len())),
+        };
+        if let Some((message, _)) = &message {
+            log::debug!("{self} sent {message:?}");
+        }
+        Ok(message)
+    }
+
     /// Send message to remote client
     fn message_client(&self, message: ServerMessage) -> Result<()> {
-        MessageToClient::new(MessageAuthor::Server(message))
-            .write_to(self.stream.as_ref())
+        self.write_message(&MessageToClient::new(MessageAuthor::Server(message)))
             .context("Unable to send message to {self}")
     }
 
-    /// Authenticate client using the server access token
-    pub fn authenticate(&mut self, access_token: Token) -> Result<()> {
-        self.message_client(ServerMessage::Text("Provide access token.".to_owned()))
-            .context("Unable to send token challenge")?;
-        let bytes = self.read_stream()?;
-        let token_str = parse_text(bytes)?;
-        if Token::from_str(&token_str)? == access_token {
-            log::info!("{self} successfully authenticated");
-            self.message_client(ServerMessage::Text(
-                "Welcome to the chat server!".to_owned(),
-            ))
-            .context("Unable to send welcome message")?;
-            Ok(())
-        } else {
-            bail!("Invalid token")
+    /// Negotiate protocol version and capabilities with remote client
+    async fn handshake(&mut self) -> Result<()> {
+        let reply = match self.read_message::<Hello>().await {
+            Ok(Some(hello)) => {
+                log::debug!("{self} hello: {hello:?}");
+                HelloReply::negotiate(&hello, SUPPORTED_CAPABILITIES)
+            }
+            Ok(None) => bail!("Connection closed before handshake"),
+            Err(e) => {
+                log::debug!("{self} sent invalid hello: {e}");
+                HelloReply::Rejected {
+                    version: PROTOCOL_VERSION,
+                    min_version: MIN_PROTOCOL_VERSION,
+                    reason: "Expected protocol hello".to_owned(),
+                }
+            }
+        };
+        self.write_message(&reply)
+            .context("Unable to send hello reply")?;
+
+        match reply {
+            HelloReply::Accepted {
+                version,
+                capabilities,
+            } => {
+                log::info!(
+                    "{self} negotiated protocol version {version} with capabilities {capabilities:?}"
+                );
+                self.capabilities = capabilities;
+                Ok(())
+            }
+            HelloReply::Rejected { reason, .. } => bail!("Incompatible protocol: {reason}"),
         }
     }
 
-    fn read_stream(&mut self) -> Result<&[u8]> {
-        log::trace!("{self} attempting to read from stream");
-        // ciborium::from_reader_with_buffer(self.stream.as_ref(), &mut self.buffer)
-        //     .context("Unable to deserialize incomeing data")
-        // TODO: Sanitize data
-        let n = self
-            .stream
-            .as_ref()
-            .read(&mut self.buffer)
-            .context("Unable to read from stream")?;
-        log::debug!("{self} read {n} bytes into buffer");
-        Ok(&self.buffer[0..n])
-    }
-
-    /// Limit rate of messages sent from Client
-    fn rate_limiter(&mut self) -> Result<bool> {
-        let message_time = Utc::now();
-        if message_time.signed_duration_since(self.last_message_time) < MESSAGE_COOLDOWN_TIME {
-            // Client is spamming, add strike
-            self.strike_count += 1;
-            log::info!(
-                "{self}: Strike {n}/{total}",
-                n = self.strike_count,
-                total = MAX_STRIKE_COUNT
+    /// Authenticate client using one of the server access tokens, or a signed token over TLS.
+    /// Returns the nickname to connect with, if any, and the role granted by the token.
+    pub async fn authenticate(&mut self, tokens: &AccessTokens) -> Result<(Option<String>, Role)> {
+        let nonce = auth::generate_nonce()?;
+        self.message_client(ServerMessage::AuthChallenge {
+            nonce: nonce.to_vec(),
+        })
+        .context("Unable to send token challenge")?;
+        let (nickname, role) = match self.read_message().await? {
+            Some(MessageToServer::Auth { response, nickname }) => {
+                let Some(access_token) = tokens.verify(&nonce, &response) else {
+                    self.record_failure("token")?;
+                    bail!("Invalid token")
+                };
+                let role = access_token.role;
+                log::info!(
+                    "{self} successfully authenticated with token {label} as {role}",
+                    label = access_token.label
+                );
+                (nickname, role)
+            }
+            Some(MessageToServer::AuthSigned { token }) => {
+                // Anyone seeing the token on the wire could replay it until it expires
+                if !self.tls {
+                    self.message_client(ServerMessage::Error(
+                        "Signed tokens are only accepted over TLS".to_owned(),
+                    ))?;
+                    bail!("Signed token sent over an unencrypted connection")
+                }
+                let claims = match tokens.verify_signed(&token.0) {
+                    Ok(claims) => claims,
+                    Err(e) => {
+                        self.record_failure("signed token")?;
+                        return Err(e.context("Invalid signed token"));
+                    }
+                };
+                log::info!(
+                    "{self} successfully authenticated with signed token of {username} as {role}, expiring at {expires_at}",
+                    username = claims.username,
+                    role = claims.role,
+                    expires_at = claims.expires_at
+                );
+                self.identify(&claims.username)?;
+                (None, claims.role)
+            }
+            Some(message) => bail!("Expected authentication message, got {message:?}"),
+            None => bail!("Connection closed before authentication"),
+        };
+        self.message_client(ServerMessage::Text(
+            "Welcome to the chat server!".to_owned(),
+        ))
+        .context("Unable to send welcome message")?;
+        Ok((nickname, role))
+    }
+
+    /// Authenticate client by the common name of its certificate, with the role of a regular user
+    fn authenticate_certificate(&mut self, identity: String) -> Result<(Option<String>, Role)> {
+        if let Err(e) = validate_nickname(&identity) {
+            self.message_client(ServerMessage::Error(format!(
+                "Certificate common name is not a valid nickname: {e:#}"
+            )))?;
+            bail!("Certificate common name {identity:?} is not a valid nickname: {e:#}")
+        }
+        log::info!("{self} successfully authenticated with client certificate of {identity}");
+        self.message_client(ServerMessage::CertificateAccepted {
+            identity: identity.clone(),
+        })
+        .context("Unable to accept client certificate")?;
+        self.message_client(ServerMessage::Text(
+            "Welcome to the chat server!".to_owned(),
+        ))
+        .context("Unable to send welcome message")?;
+        self.identify(&identity)?;
+        Ok((None, Role::User))
+    }
+
+    /// Claim account of name proven by certificate or signed token, logged into once connected.
+    /// Names registered with a password are never handed over, the client connects without one.
+    fn identify(&mut self, identity: &str) -> Result<()> {
+        match self.accounts.claim_identity(identity) {
+            Ok(account) => self.identity = Some(account),
+            Err(e) => {
+                log::warn!("{self} connecting without the name {identity}: {e:#}");
+                self.message_client(ServerMessage::Error(format!(
+                    "Name {identity} is taken by another account, connecting without it"
+                )))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record failed authentication attempt, locking the address out after too many
+    fn record_failure(&self, attempt: &str) -> Result<()> {
+        let policy = self.config.borrow().lockout;
+        let ip = self.addr.ip();
+        match self.auth_failures.record(ip, &policy) {
+            Failure::Delayed { failures: 1, .. } => {
+                log::info!("IP {ip} failed {attempt} authentication");
+                Ok(())
+            }
+            Failure::Delayed { failures, delay } => {
+                log::warn!(
+                    "IP {ip} failed {attempt} authentication {failures} times, next attempt delayed by {ms} ms",
+                    ms = delay.as_millis()
+                );
+                Ok(())
+            }
+            Failure::LockedOut { failures, duration } => {
+                log::warn!(
+                    "IP {ip} locked out for {secs} seconds after failing {attempt} authentication {failures} times",
+                    secs = duration.as_secs()
+                );
+                self.send_request(Request::Lockout(duration))
+            }
+        }
+    }
+
+    /// Run password check of account, slowing down and locking out repeated failures.
+    /// Other clients may share the address, so only the account is locked out.
+    async fn check_password<T>(
+        &self,
+        username: &str,
+        check: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let policy = self.config.borrow().lockout;
+        let key = username.to_lowercase();
+        if let Some(remaining) = self.login_failures.locked_for(&key) {
+            bail!(
+                "Too many failed password attempts for {username}, try again in {secs} seconds",
+                secs = remaining.as_secs()
+            )
+        }
+        let delay = self.login_failures.delay(&key, &policy);
+        if !delay.is_zero() {
+            log::debug!(
+                "{self} password check of {username} delayed {ms} ms after failed attempts",
+                ms = delay.as_millis()
             );
-            if self.strike_count >= MAX_STRIKE_COUNT {
-                // Ban offending client
-                self.strike_count = 0;
-                return Ok(true);
+            time::sleep(delay).await;
+        }
+        let result = check.await;
+        if result.is_err() {
+            match self.login_failures.record(key, &policy) {
+                Failure::Delayed { failures, .. } => log::info!(
+                    "{self} failed password check of {username}, {failures} recent failures"
+                ),
+                Failure::LockedOut { failures, duration } => log::warn!(
+                    "Password checks of {username} locked for {secs} seconds after {failures} failures",
+                    secs = duration.as_secs()
+                ),
+            }
+        }
+        result
+    }
+
+    /// Apply limits of the current server configuration
+    fn update_limits(&mut self) {
+        self.limits = self.config.borrow_and_update().limits;
+        self.rate_limiter.update(&self.limits.rate_limits);
+        log::debug!("{self} limits updated");
+    }
+
+    /// Apply rate limits to incoming message of `size` bytes.
+    /// Returns whether the message should be handled.
+    async fn rate_limit(&mut self, traffic: Traffic, size: usize) -> Result<bool> {
+        let Err(exceeded) = self.rate_limiter.check(traffic, size) else {
+            return Ok(true);
+        };
+        let limits = self.limits.rate_limits;
+        log::info!(
+            "{self} exceeded {exceeded}, consequence: {consequence:?}",
+            consequence = limits.consequence
+        );
+        match limits.consequence {
+            Consequence::Warn => {
+                self.message_client(ServerMessage::Text(format!(
+                    "Slow down! You exceeded the {exceeded} and your message was discarded."
+                )))?;
+                Ok(false)
+            }
+            Consequence::Throttle => {
+                // Stop reading until the message fits within the limits
+                time::sleep(self.rate_limiter.wait_time(traffic, size)).await;
+                self.rate_limiter.take(traffic, size);
+                Ok(true)
+            }
+            Consequence::Mute => {
+                self.send_request(Request::Mute(limits.mute_duration))?;
+                Ok(false)
+            }
+            Consequence::Ban => {
+                self.send_request(Request::Ban(BanReason::Spamming))?;
+                Ok(false)
             }
-        } else {
-            // Reset strikes
-            self.strike_count = 0;
         }
-        self.last_message_time = message_time;
-        Ok(false)
     }
 
     /// Send a Request to the Server
@@ -156,10 +421,16 @@ impl Client {
     }
 
     /// Send Connect Request to Server
-    fn request_connect(&self) -> Result<()> {
+    fn request_connect(&self, nickname: Option<String>, role: Role) -> Result<()> {
         log::trace!("{self} sending Connect Request");
-        self.send_request(Request::Connect(self.stream.clone()))
-            .context("{self} unable to send Connect Request to Server")
+        self.send_request(Request::Connect {
+            outbound: self.outbound.clone(),
+            nickname,
+            capabilities: self.capabilities.clone(),
+            role,
+            tls: self.tls,
+        })
+        .context("{self} unable to send Connect Request to Server")
     }
 
     /// Send Disconnect Request to Server
@@ -176,56 +447,235 @@ impl Client {
             .context("{self} unable to send text message to Server")
     }
 
-    /// Run client
-    pub fn run(&mut self, access_token: Token) -> Result<()> {
-        log::trace!("Spawned thread for {self}");
+    /// Bind account to the session, or let the client know why it could not log in
+    fn bind_account(&mut self, account: Result<Account>) -> Result<()> {
+        match account {
+            Ok(account) => {
+                self.account = Some(account.username.clone());
+                self.send_request(Request::Login {
+                    username: account.username,
+                    role: account.role,
+                })
+            }
+            Err(e) => {
+                log::warn!("{self} failed to log in: {e:#}");
+                self.message_client(ServerMessage::Error(format!("{e:#}")))
+            }
+        }
+    }
+
+    /// Negotiate protocol and authenticate remote client.
+    /// Returns the nickname requested by the client, if any, and its role.
+    async fn establish(&mut self, tokens: &AccessTokens) -> Result<(Option<String>, Role)> {
+        // Negotiate protocol version and capabilities
+        self.handshake().await?;
+
+        // Refuse addresses locked out after too many failed attempts
+        if let Some(remaining) = self.auth_failures.locked_for(&self.addr.ip()) {
+            let remaining_secs = i64::try_from(remaining.as_secs()).unwrap_or(i64::MAX);
+            self.message_client(ServerMessage::Banned {
+                reason: BanReason::FailedLogins,
+                remaining_secs: Some(remaining_secs),
+            })?;
+            bail!("Locked out for {remaining_secs} more seconds");
+        }
+
+        // Clients identified by their certificate need no token
+        if let Some(identity) = self.certificate.clone() {
+            return self.authenticate_certificate(identity);
+        }
 
         // Authenticate client using server access token
-        if let Err(e) = self.authenticate(access_token) {
-            self.message_client(ServerMessage::Text("Failed to authenticate!".to_owned()))?;
-            log::error!("{self} failed to authenticate: {e}");
-            return Err(e);
+        match self.authenticate(tokens).await {
+            Ok(identity) => Ok(identity),
+            Err(e) => {
+                self.message_client(ServerMessage::Text("Failed to authenticate!".to_owned()))?;
+                log::error!("{self} failed to authenticate: {e}");
+                Err(e)
+            }
         }
+    }
+
+    /// Run client.
+    /// The `unauthenticated` permit is held until the client is authenticated.
+    pub async fn run(
+        &mut self,
+        tokens: Arc<AccessTokens>,
+        unauthenticated: OwnedSemaphorePermit,
+    ) -> Result<()> {
+        log::trace!("Spawned task for {self}");
+
+        // Handshake and authentication must complete before the deadline
+        let handshake_timeout = self.limits.handshake_timeout;
+        let (nickname, role) = match time::timeout(handshake_timeout, self.establish(&tokens)).await
+        {
+            Ok(result) => result?,
+            Err(_) => {
+                log::warn!(
+                    "{self} did not authenticate within {secs} seconds, closing connection",
+                    secs = handshake_timeout.as_secs()
+                );
+                bail!("Handshake timed out");
+            }
+        };
+        drop(unauthenticated);
 
         // Send connection request to server
-        self.request_connect()?;
+        self.request_connect(nickname, role)?;
+
+        // Log clients identified by certificate or signed token into their account
+        if let Some(account) = self.identity.take() {
+            self.bind_account(Ok(account))?;
+        }
+        let outbound = self.outbound.clone();
 
         // Chat loop
         loop {
-            // Message rate limit
-            if self.rate_limiter()? {
-                return self.send_request(Request::Ban(BanReason::Spamming));
+            // Read incoming message unless the server dropped the client
+            let idle_timeout = self.limits.idle_timeout;
+            let message = tokio::select! {
+                message = time::timeout(idle_timeout, self.read_sized_message()) => message,
+                _ = outbound.closed() => {
+                    log::debug!("{addr} connection closed", addr = self.addr);
+                    return Ok(());
+                }
+            };
+            let Ok(message) = message else {
+                log::warn!(
+                    "{self} idle for {secs} seconds, closing connection",
+                    secs = idle_timeout.as_secs()
+                );
+                let _ = self.message_client(ServerMessage::Text(
+                    "Idle timeout, disconnecting.".to_owned(),
+                ));
+                self.request_disconnect()?;
+                bail!("Idle timeout");
+            };
+            let (message, size) = match message {
+                Ok(None) => {
+                    log::debug!("{self} reached EOF");
+                    return self.request_disconnect();
+                }
+                Ok(Some(sized)) => sized,
+                Err(e) => {
+                    // Reject oversized frames before dropping the connection
+                    if let Some(FrameError::TooLarge(size)) = e.downcast_ref::<FrameError>() {
+                        log::warn!("{self} sent oversized frame of {size} bytes");
+                        let _ = self.message_client(ServerMessage::Text(
+                            "Message too large, disconnecting.".to_owned(),
+                        ));
+                    }
+                    self.request_disconnect()?;
+                    return Err(e);
+                }
+            };
+
+            // Pick up reloaded configuration
+            if self.config.has_changed().unwrap_or(false) {
+                self.update_limits();
             }
 
-            // Read incoming data
-            let bytes = self.read_stream()?;
+            // Rate limit
+            let traffic = match message {
+                MessageToServer::Text(_) | MessageToServer::Encrypted { .. } => Traffic::Message,
+                MessageToServer::Command { .. }
+                | MessageToServer::PublishKey(_)
+                | MessageToServer::Register { .. }
+                | MessageToServer::Login { .. }
+                | MessageToServer::ChangePassword { .. } => Traffic::Command,
+                _ => Traffic::Other,
+            };
+            if !self.rate_limit(traffic, size).await? {
+                continue;
+            }
 
-            if bytes.is_empty() {
-                log::debug!("{self} reached EOF");
-                return self.request_disconnect();
-            } else {
-                // Handle data read from stream
-                match parse_text(bytes) {
-                    Err(e) => {
-                        log::error!("{self} could not parse text: {e}");
+            // Handle message
+            match message {
+                MessageToServer::Text(text) => {
+                    let text = sanitize_text(&text);
+                    if text.is_empty() {
+                        continue;
                     }
-                    Ok(text) => {
-                        log::debug!("{self} says: {text}");
-                        if let Err(e) = self.broadcast_text(text) {
-                            log::error!("{self} could not send text Message to server: {e}");
-                        };
+                    log::debug!("{self} says: {text}");
+                    if let Err(e) = self.broadcast_text(text) {
+                        log::error!("{self} could not send text Message to server: {e}");
+                    };
+                }
+                MessageToServer::Command { name, args } => {
+                    let args = sanitize_text(&args);
+                    log::debug!("{self} runs command /{name} {args}");
+                    self.send_request(Request::Command { name, args })?;
+                }
+                MessageToServer::PublishKey(key) => {
+                    self.send_request(Request::PublishKey(key))?;
+                }
+                MessageToServer::Encrypted { recipient, payload } => {
+                    log::debug!("{self} sends encrypted message to {recipient}");
+                    self.send_request(Request::Encrypted { recipient, payload })?;
+                }
+                MessageToServer::Ping => {
+                    self.message_client(ServerMessage::Pong)?;
+                }
+                MessageToServer::Auth { .. } | MessageToServer::AuthSigned { .. } => {
+                    self.message_client(ServerMessage::Text("Already authenticated".to_owned()))?;
+                }
+                MessageToServer::Register { .. } | MessageToServer::Login { .. }
+                    if self.account.is_some() =>
+                {
+                    self.message_client(ServerMessage::Error(format!(
+                        "Already logged in as {username}",
+                        username = self.account.as_deref().unwrap_or_default()
+                    )))?;
+                }
+                MessageToServer::Register { username, password } => {
+                    let account = self.accounts.register(&username, password.0).await;
+                    self.bind_account(account)?;
+                }
+                MessageToServer::Login { username, password } => {
+                    let account = self
+                        .check_password(&username, self.accounts.login(&username, password.0))
+                        .await;
+                    self.bind_account(account)?;
+                }
+                MessageToServer::ChangePassword {
+                    old_password,
+                    new_password,
+                } => {
+                    let Some(username) = self.account.clone() else {
+                        self.message_client(ServerMessage::Error(
+                            "Log in to change your password".to_owned(),
+                        ))?;
+                        continue;
+                    };
+                    // Only wrong old passwords count as failures
+                    let changed = match validate_password(&new_password.0) {
+                        Ok(()) => {
+                            let change = self.accounts.change_password(
+                                &username,
+                                old_password.0,
+                                new_password.0,
+                            );
+                            self.check_password(&username, change).await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match changed {
+                        Ok(()) => {
+                            self.message_client(ServerMessage::Text("Password changed".to_owned()))?
+                        }
+                        Err(e) => {
+                            log::warn!("{self} failed to change password: {e:#}");
+                            self.message_client(ServerMessage::Error(format!("{e:#}")))?
+                        }
                     }
                 }
             }
         }
     }
 
-    /// Shutdown client
-    pub fn shutdown(&self) -> Result<()> {
+    /// Shutdown client, writing any queued messages first
+    pub fn shutdown(&self) {
         log::debug!("Shutting down {self} stream");
-        self.stream
-            .as_ref()
-            .shutdown(std::net::Shutdown::Both)
-            .context("{self} was unable to shutdown stream")
+        self.outbound.close();
     }
 }
@@ -0,0 +1,375 @@
+use std::{collections::BTreeMap, fmt::Display, net::SocketAddr};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    bans::parse_range,
+    messages::{Capability, CommandInfo, Recipient},
+    rooms::DEFAULT_ROOM,
+    server::Server,
+    session::Role,
+    utils::parse_duration,
+};
+
+/// Command handler, run by the server on behalf of the client at the given address
+pub type Handler = fn(&mut Server, SocketAddr, &Args) -> Result<()>;
+
+/// Argument expected by a command
+#[derive(Debug, Clone, Copy)]
+pub enum Arg {
+    /// Single word which must be present
+    Required(&'static str),
+    /// Single word which may be omitted
+    Optional(&'static str),
+    /// Rest of the input, kept as typed. Must not be empty.
+    Rest(&'static str),
+    /// Rest of the input, kept as typed, which may be omitted
+    OptionalRest(&'static str),
+}
+
+impl Display for Arg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Arg::Required(name) => write!(f, "<{name}>"),
+            Arg::Optional(name) => write!(f, "[{name}]"),
+            Arg::Rest(name) => write!(f, "<{name}...>"),
+            Arg::OptionalRest(name) => write!(f, "[{name}...]"),
+        }
+    }
+}
+
+/// Arguments parsed according to the command specification
+#[derive(Debug)]
+pub struct Args(Vec<Option<String>>);
+
+impl Args {
+    /// Argument at position, if given
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.0.get(index)?.as_deref()
+    }
+
+    /// Required argument at position.
+    ///
+    /// Panics if the command specification does not declare it as required.
+    pub fn required(&self, index: usize) -> &str {
+        self.get(index).expect("Required argument missing")
+    }
+}
+
+/// Split first word off input
+fn next_word(input: &str) -> Option<(&str, &str)> {
+    let input = input.trim_start();
+    if input.is_empty() {
+        return None;
+    }
+    Some(input.split_once(char::is_whitespace).unwrap_or((input, "")))
+}
+
+/// Parse raw argument string. Returns `None` if the arguments do not match the specification.
+fn parse_args(spec: &[Arg], input: &str) -> Option<Args> {
+    let mut rest = input;
+    let mut args = Vec::with_capacity(spec.len());
+    for arg in spec {
+        match arg {
+            Arg::Required(_) => {
+                let (word, remainder) = next_word(rest)?;
+                args.push(Some(word.to_owned()));
+                rest = remainder;
+            }
+            Arg::Optional(_) => match next_word(rest) {
+                Some((word, remainder)) => {
+                    args.push(Some(word.to_owned()));
+                    rest = remainder;
+                }
+                None => args.push(None),
+            },
+            Arg::Rest(_) => {
+                let text = rest.trim();
+                if text.is_empty() {
+                    return None;
+                }
+                args.push(Some(text.to_owned()));
+                rest = "";
+            }
+            Arg::OptionalRest(_) => {
+                let text = rest.trim();
+                args.push((!text.is_empty()).then(|| text.to_owned()));
+                rest = "";
+            }
+        }
+    }
+    // Reject extra arguments
+    if !rest.trim().is_empty() {
+        return None;
+    }
+    Some(Args(args))
+}
+
+/// Command which can be run by clients
+#[derive(Debug, Clone, Copy)]
+pub struct Command {
+    /// Name typed after the slash
+    pub name: &'static str,
+    /// Expected arguments
+    pub args: &'static [Arg],
+    /// Short explanation shown by `/help`
+    pub description: &'static str,
+    /// Lowest role allowed to run the command
+    pub permission: Role,
+    /// Capability the client must have negotiated
+    pub capability: Option<Capability>,
+    pub handler: Handler,
+}
+
+impl Command {
+    /// Usage line, e.g. `/msg <recipient> <text...>`
+    pub fn usage(&self) -> String {
+        let mut usage = format!("/{name}", name = self.name);
+        for arg in self.args {
+            usage.push_str(&format!(" {arg}"));
+        }
+        usage
+    }
+
+    /// Parse raw argument string
+    pub fn parse_args(&self, input: &str) -> Option<Args> {
+        parse_args(self.args, input)
+    }
+
+    /// Description sent to clients
+    pub fn info(&self) -> CommandInfo {
+        CommandInfo {
+            name: self.name.to_owned(),
+            usage: self.usage(),
+            description: self.description.to_owned(),
+        }
+    }
+}
+
+/// Commands known by the server, by name
+#[derive(Debug, Default)]
+pub struct CommandRegistry {
+    commands: BTreeMap<&'static str, Command>,
+}
+
+impl CommandRegistry {
+    /// New empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry with all built-in commands
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        for command in BUILTIN_COMMANDS {
+            registry.register(*command);
+        }
+        registry
+    }
+
+    /// Add command, replacing any command with the same name
+    pub fn register(&mut self, command: Command) {
+        if self.commands.insert(command.name, command).is_some() {
+            log::warn!("Command /{name} registered twice", name = command.name);
+        }
+    }
+
+    /// Command by name
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.get(name)
+    }
+
+    /// Commands available to role, sorted by name
+    pub fn available(&self, role: Role) -> impl Iterator<Item = &Command> {
+        self.commands
+            .values()
+            .filter(move |command| role >= command.permission)
+    }
+}
+
+/// Commands registered on every server
+const BUILTIN_COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        args: &[Arg::Optional("command")],
+        description: "List commands or show how to use one",
+        permission: Role::User,
+        capability: None,
+        handler: |server, addr, args| server.help(addr, args.get(0)),
+    },
+    Command {
+        name: "nick",
+        args: &[Arg::Required("nickname")],
+        description: "Change your nickname",
+        permission: Role::User,
+        capability: None,
+        handler: |server, addr, args| server.change_nickname(addr, args.required(0).to_owned()),
+    },
+    Command {
+        name: "msg",
+        args: &[Arg::Required("recipient"), Arg::Rest("text")],
+        description: "Send private message to a user by nickname or #ID",
+        permission: Role::User,
+        capability: None,
+        handler: |server, addr, args| {
+            let recipient = Recipient::parse(args.required(0));
+            server.direct_message(addr, &recipient, args.required(1))
+        },
+    },
+    Command {
+        name: "who",
+        args: &[],
+        description: "List users in your room",
+        permission: Role::User,
+        capability: None,
+        handler: |server, addr, _| server.list_users(addr),
+    },
+    Command {
+        name: "rooms",
+        args: &[],
+        description: "List rooms",
+        permission: Role::User,
+        capability: Some(Capability::Rooms),
+        handler: |server, addr, _| server.list_rooms(addr),
+    },
+    Command {
+        name: "create",
+        args: &[Arg::Required("room")],
+        description: "Create room and join it",
+        permission: Role::User,
+        capability: Some(Capability::Rooms),
+        handler: |server, addr, args| server.create_room(addr, args.required(0)),
+    },
+    Command {
+        name: "join",
+        args: &[Arg::Required("room")],
+        description: "Join room",
+        permission: Role::User,
+        capability: Some(Capability::Rooms),
+        handler: |server, addr, args| server.join_room(addr, args.required(0)),
+    },
+    Command {
+        name: "leave",
+        args: &[],
+        description: "Go back to the lobby",
+        permission: Role::User,
+        capability: Some(Capability::Rooms),
+        handler: |server, addr, _| server.join_room(addr, DEFAULT_ROOM),
+    },
+    Command {
+        name: "kick",
+        args: &[Arg::Required("user"), Arg::OptionalRest("reason")],
+        description: "Disconnect user",
+        permission: Role::Moderator,
+        capability: None,
+        handler: |server, addr, args| {
+            server.kick(addr, &Recipient::parse(args.required(0)), args.get(1))
+        },
+    },
+    Command {
+        name: "ban",
+        args: &[
+            Arg::Required("user|range"),
+            Arg::Required("duration"),
+            Arg::OptionalRest("reason"),
+        ],
+        description: "Ban user or CIDR range for a duration such as 30m, 2h or 7d, or permanently",
+        permission: Role::Moderator,
+        capability: None,
+        handler: |server, addr, args| {
+            let duration = match args.required(1) {
+                "permanent" | "perm" => None,
+                duration => Some(parse_duration(duration)?),
+            };
+            match parse_range(args.required(0)) {
+                Some(range) => server.ban_range(addr, range, duration, args.get(2)),
+                None => server.ban(
+                    addr,
+                    &Recipient::parse(args.required(0)),
+                    duration,
+                    args.get(2),
+                ),
+            }
+        },
+    },
+    Command {
+        name: "unban",
+        args: &[Arg::Required("range")],
+        description: "Lift bans of IP address or CIDR range, and of accounts banned with it",
+        permission: Role::Moderator,
+        capability: None,
+        handler: |server, addr, args| {
+            let range = parse_range(args.required(0)).ok_or(anyhow!(
+                "Invalid IP address or range {range}",
+                range = args.required(0)
+            ))?;
+            server.unban(addr, range)
+        },
+    },
+    Command {
+        name: "bans",
+        args: &[],
+        description: "List bans in effect",
+        permission: Role::Moderator,
+        capability: None,
+        handler: |server, addr, _| server.list_bans(addr),
+    },
+    Command {
+        name: "mute",
+        args: &[
+            Arg::Required("user"),
+            Arg::Required("duration"),
+            Arg::OptionalRest("reason"),
+        ],
+        description: "Prevent user from sending messages for a duration",
+        permission: Role::Moderator,
+        capability: None,
+        handler: |server, addr, args| {
+            let duration = parse_duration(args.required(1))?;
+            server.mute(
+                addr,
+                &Recipient::parse(args.required(0)),
+                duration,
+                args.get(2),
+            )
+        },
+    },
+    Command {
+        name: "unmute",
+        args: &[Arg::Required("user")],
+        description: "Allow muted user to send messages again",
+        permission: Role::Moderator,
+        capability: None,
+        handler: |server, addr, args| server.unmute(addr, &Recipient::parse(args.required(0))),
+    },
+    Command {
+        name: "role",
+        args: &[Arg::Required("user"), Arg::Required("role")],
+        description: "Make user a user, moderator or admin",
+        permission: Role::Admin,
+        capability: None,
+        handler: |server, addr, args| {
+            let role = args.required(1).parse()?;
+            server.set_role(addr, &Recipient::parse(args.required(0)), role)
+        },
+    },
+    Command {
+        name: "rotate",
+        args: &[Arg::Required("token"), Arg::Optional("grace")],
+        description: "Replace access token, accepting the old one for a grace period such as 1h",
+        permission: Role::Admin,
+        capability: None,
+        handler: |server, addr, args| {
+            let grace = args.get(1).map(parse_duration).transpose()?;
+            server.rotate_token(addr, args.required(0), grace)
+        },
+    },
+    Command {
+        name: "reload",
+        args: &[],
+        description: "Reload server configuration",
+        permission: Role::Admin,
+        capability: None,
+        handler: |server, addr, _| server.reload(addr),
+    },
+];
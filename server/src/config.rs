@@ -0,0 +1,661 @@
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::TimeDelta;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    outbound::OverflowPolicy,
+    session::Role,
+    signed_tokens::TokenVerifier,
+    utils::{check_duration, parse_duration},
+};
+
+/// Port listened to by default
+pub const DEFAULT_PORT: u16 = 6969;
+
+/// Server configuration, usually loaded from a TOML file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to accept connections on
+    pub listeners: Vec<SocketAddr>,
+    /// Encrypt connections of every listener if set
+    pub tls: Option<TlsConfig>,
+    /// Message of the day shown to clients when they connect
+    pub motd: Option<String>,
+    pub limits: ConnectionLimits,
+    pub bans: BanConfig,
+    pub auth: AuthConfig,
+    pub lockout: LockoutPolicy,
+    pub accounts: AccountConfig,
+    pub logging: LoggingConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listeners: vec![SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                DEFAULT_PORT,
+            )],
+            tls: None,
+            motd: None,
+            limits: ConnectionLimits::default(),
+            bans: BanConfig::default(),
+            auth: AuthConfig::default(),
+            lockout: LockoutPolicy::default(),
+            accounts: AccountConfig::default(),
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Load configuration from TOML file. Missing values take their defaults.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read config file {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Check values are usable
+    pub fn validate(&self) -> Result<()> {
+        if self.listeners.is_empty() {
+            bail!("At least one listener address is required")
+        }
+        self.limits.validate()?;
+        self.bans.escalation.validate()?;
+        self.auth.validate()?;
+        self.lockout.validate()?;
+        if let Some(tls) = &self.tls {
+            tls.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Source the configuration is loaded from, used again when reloading
+pub struct ConfigSource(Box<dyn Fn() -> Result<Config> + Send>);
+
+impl ConfigSource {
+    /// New source calling `load` to build the configuration
+    pub fn new(load: impl Fn() -> Result<Config> + Send + 'static) -> Self {
+        Self(Box::new(load))
+    }
+
+    /// Load and validate configuration
+    pub fn load(&self) -> Result<Config> {
+        let config = (self.0)()?;
+        config.validate().context("Invalid configuration")?;
+        Ok(config)
+    }
+}
+
+impl std::fmt::Debug for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ConfigSource")
+    }
+}
+
+/// Certificate the server presents to clients
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, starting with the server certificate
+    pub cert: PathBuf,
+    /// PEM file with the private key of the certificate
+    pub key: PathBuf,
+    /// PEM file with the CA certificates client certificates must be signed by.
+    /// Clients presenting one are identified by its subject common name instead of a token.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// Refuse clients without a certificate signed by `client_ca`
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
+impl TlsConfig {
+    fn validate(&self) -> Result<()> {
+        if self.require_client_cert && self.client_ca.is_none() {
+            bail!("Requiring client certificates needs a client CA")
+        }
+        Ok(())
+    }
+}
+
+/// Where bans are stored and how automatic bans escalate
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BanConfig {
+    /// File bans are persisted to
+    pub file: PathBuf,
+    pub escalation: EscalationPolicy,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::from("bans.toml"),
+            escalation: EscalationPolicy::default(),
+        }
+    }
+}
+
+/// Access token settings
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Length of generated access tokens in bytes
+    pub token_length: usize,
+    /// Tokens accepted. Random user and admin tokens are generated if empty
+    /// and signed tokens are not enabled either.
+    pub tokens: Vec<TokenConfig>,
+    /// Base64 Ed25519 public key verifying signed tokens. Signed tokens are refused if unset.
+    pub signed_token_key: Option<String>,
+    /// How long rotated tokens keep working
+    #[serde(deserialize_with = "deserialize_time_delta")]
+    pub rotation_grace: TimeDelta,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            token_length: 16,
+            tokens: Vec::new(),
+            signed_token_key: None,
+            rotation_grace: TimeDelta::hours(1),
+        }
+    }
+}
+
+/// Access token accepted by the server
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TokenConfig {
+    /// Name identifying the token in logs and commands
+    pub label: String,
+    /// Role granted by the token
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    pub role: Role,
+    #[serde(flatten)]
+    pub source: TokenSource,
+}
+
+/// Where an access token is read from, as a hex string
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenSource {
+    /// Written in the configuration itself
+    Token(String),
+    /// File holding the token, rewritten when the token is rotated
+    File(PathBuf),
+    /// Environment variable holding the token
+    Env(String),
+}
+
+impl AuthConfig {
+    /// Shortest token length accepted, in bytes
+    pub const MIN_TOKEN_LENGTH: usize = 4;
+    /// Longest token length accepted, in bytes
+    pub const MAX_TOKEN_LENGTH: usize = 64;
+
+    fn validate(&self) -> Result<()> {
+        if !(Self::MIN_TOKEN_LENGTH..=Self::MAX_TOKEN_LENGTH).contains(&self.token_length) {
+            bail!(
+                "Token length must be between {min} and {max} bytes, got {length}",
+                min = Self::MIN_TOKEN_LENGTH,
+                max = Self::MAX_TOKEN_LENGTH,
+                length = self.token_length
+            )
+        }
+        if let Some(key) = &self.signed_token_key {
+            key.parse::<TokenVerifier>()
+                .context("Invalid signed token key")?;
+        }
+        for (i, token) in self.tokens.iter().enumerate() {
+            if token.label.is_empty() {
+                bail!("Token labels must not be empty")
+            }
+            if self.tokens[..i]
+                .iter()
+                .any(|other| other.label == token.label)
+            {
+                bail!(
+                    "Token label {label} used more than once",
+                    label = token.label
+                )
+            }
+        }
+        check_durations(&[("token rotation grace", self.rotation_grace)])
+    }
+}
+
+/// How addresses failing to authenticate, and accounts given wrong passwords,
+/// are slowed down and locked out
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutPolicy {
+    /// Failed attempts from an address, or password attempts on an account, before it is locked out
+    pub max_failures: u32,
+    /// Wait before the next attempt after a first failure, doubled by each further failure
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub base_delay: Duration,
+    /// Longest wait between attempts
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub max_delay: Duration,
+    /// How long locked out addresses are banned, and locked out accounts refuse passwords, for
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub duration: Duration,
+    /// Failures are forgotten after this long without new ones
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub window: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            duration: Duration::from_secs(15 * 60),
+            window: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl LockoutPolicy {
+    fn validate(&self) -> Result<()> {
+        if self.max_failures == 0 {
+            bail!("Lockout must allow at least one failed attempt")
+        }
+        if self.base_delay > self.max_delay {
+            bail!("Base lockout delay must not exceed the maximum delay")
+        }
+        if self.duration.is_zero() {
+            bail!("Lockout duration must be positive")
+        }
+        check_durations(&[
+            ("lockout base delay", time_delta(self.base_delay)),
+            ("lockout maximum delay", time_delta(self.max_delay)),
+            ("lockout duration", time_delta(self.duration)),
+            ("lockout window", time_delta(self.window)),
+        ])
+    }
+
+    /// Wait before the next attempt after `failures` recent failures
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(failures - 1))
+            .min(self.max_delay)
+    }
+}
+
+/// Where registered user accounts are stored
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountConfig {
+    /// File accounts are persisted to
+    pub file: PathBuf,
+}
+
+impl Default for AccountConfig {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::from("accounts.toml"),
+        }
+    }
+}
+
+/// Log output settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Most verbose level logged. Overridden by the `RUST_LOG` environment variable.
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub level: LevelFilter,
+    /// Color log levels
+    pub colors: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Info,
+            colors: true,
+        }
+    }
+}
+
+/// Deserialize value from its string representation
+fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Check configured durations do not exceed `MAX_DURATION`, as times computed from them would
+fn check_durations(durations: &[(&str, TimeDelta)]) -> Result<()> {
+    for (name, duration) in durations {
+        check_duration(*duration).with_context(|| format!("Invalid {name}"))?;
+    }
+    Ok(())
+}
+
+/// Convert duration for `check_durations`, saturating if out of range
+fn time_delta(duration: Duration) -> TimeDelta {
+    TimeDelta::from_std(duration).unwrap_or(TimeDelta::MAX)
+}
+
+/// Duration given either as seconds or as a string such as `30s`, `10m`, `2h` or `7d`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DurationValue {
+    Seconds(u64),
+    Text(String),
+}
+
+impl DurationValue {
+    fn to_time_delta(&self) -> Result<TimeDelta> {
+        match self {
+            DurationValue::Seconds(secs) => i64::try_from(*secs)
+                .ok()
+                .and_then(TimeDelta::try_seconds)
+                .context("Duration too long"),
+            DurationValue::Text(text) => parse_duration(text),
+        }
+    }
+}
+
+fn deserialize_time_delta<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<TimeDelta, D::Error> {
+    DurationValue::deserialize(deserializer)?
+        .to_time_delta()
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    deserialize_time_delta(deserializer)?
+        .to_std()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Connection timeouts and limits used to protect the server from slow or stuck peers
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionLimits {
+    /// Maximum time for a new connection to complete handshake and authentication
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub handshake_timeout: Duration,
+    /// Maximum time an authenticated client may stay without sending any message
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub idle_timeout: Duration,
+    /// Maximum time to write a single message to a peer
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub write_timeout: Duration,
+    /// Maximum number of concurrent connections still handshaking or authenticating
+    pub max_unauthenticated: usize,
+    /// Maximum number of messages queued for a client before the overflow policy applies
+    pub outbound_capacity: usize,
+    /// What to do when a client falls too far behind
+    pub overflow_policy: OverflowPolicy,
+    /// Limits on incoming traffic of authenticated clients
+    pub rate_limits: RateLimits,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(10 * 60),
+            write_timeout: Duration::from_secs(5),
+            max_unauthenticated: 64,
+            outbound_capacity: 256,
+            overflow_policy: OverflowPolicy::MarkLagging,
+            rate_limits: RateLimits::default(),
+        }
+    }
+}
+
+impl ConnectionLimits {
+    fn validate(&self) -> Result<()> {
+        if self.handshake_timeout.is_zero()
+            || self.idle_timeout.is_zero()
+            || self.write_timeout.is_zero()
+        {
+            bail!("Timeouts must be positive")
+        }
+        if self.max_unauthenticated == 0 {
+            bail!("At least one unauthenticated connection must be allowed")
+        }
+        if self.outbound_capacity == 0 {
+            bail!("Outbound capacity must be positive")
+        }
+        self.rate_limits.validate()
+    }
+}
+
+/// Token bucket parameters
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Bucket size, i.e. how much may be sent at once
+    pub burst: u32,
+    /// Tokens refilled per second
+    pub per_second: f64,
+}
+
+/// What happens to a client exceeding a rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Consequence {
+    /// Discard the message and warn the client
+    Warn,
+    /// Delay the message until it fits within the limits
+    Throttle,
+    /// Discard the message and mute the client
+    Mute,
+    /// Ban the client
+    Ban,
+}
+
+/// Rate limits of incoming traffic, enforced separately
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// Chat messages
+    pub messages: RateLimit,
+    /// Bytes of any incoming message
+    pub bytes: RateLimit,
+    /// Slash commands
+    pub commands: RateLimit,
+    /// What to do when any of the limits is exceeded
+    pub consequence: Consequence,
+    /// How long clients are muted for when the consequence is `Mute`
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub mute_duration: Duration,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            messages: RateLimit {
+                burst: 5,
+                per_second: 2.0,
+            },
+            bytes: RateLimit {
+                burst: 64 * 1024,
+                per_second: 8.0 * 1024.0,
+            },
+            commands: RateLimit {
+                burst: 5,
+                per_second: 1.0,
+            },
+            consequence: Consequence::Warn,
+            mute_duration: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RateLimits {
+    fn validate(&self) -> Result<()> {
+        for (name, limit) in [
+            ("messages", self.messages),
+            ("bytes", self.bytes),
+            ("commands", self.commands),
+        ] {
+            if limit.burst == 0 || !(limit.per_second.is_finite() && limit.per_second > 0.0) {
+                bail!("Rate limit of {name} must have a positive burst and refill rate")
+            }
+        }
+        if self.mute_duration.is_zero() {
+            bail!("Rate limit mute duration must be positive")
+        }
+        check_durations(&[("rate limit mute duration", time_delta(self.mute_duration))])
+    }
+}
+
+/// How automatic ban durations grow for repeat offenders
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EscalationPolicy {
+    /// Ban duration for a first offense
+    #[serde(deserialize_with = "deserialize_time_delta")]
+    pub base: TimeDelta,
+    /// Each further offense multiplies the previous duration by this factor
+    pub factor: u32,
+    /// Longest automatic ban
+    #[serde(deserialize_with = "deserialize_time_delta")]
+    pub max: TimeDelta,
+    /// Offense history is forgiven after this long without new offenses
+    #[serde(deserialize_with = "deserialize_time_delta")]
+    pub decay: TimeDelta,
+}
+
+impl Default for EscalationPolicy {
+    fn default() -> Self {
+        Self {
+            base: TimeDelta::minutes(5),
+            factor: 2,
+            max: TimeDelta::days(1),
+            decay: TimeDelta::days(7),
+        }
+    }
+}
+
+impl EscalationPolicy {
+    fn validate(&self) -> Result<()> {
+        if self.factor == 0 {
+            bail!("Ban escalation factor must be at least 1")
+        }
+        if self.base > self.max {
+            bail!("Base ban duration must not exceed the maximum ban duration")
+        }
+        check_durations(&[
+            ("maximum ban duration", self.max),
+            ("ban decay", self.decay),
+        ])
+    }
+
+    /// Ban duration for the given offense count, starting at 1
+    pub fn ban_duration(&self, offenses: u32) -> TimeDelta {
+        let multiplier = self
+            .factor
+            .checked_pow(offenses.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.base
+            .checked_mul(i32::try_from(multiplier).unwrap_or(i32::MAX))
+            .map_or(self.max, |duration| duration.min(self.max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(base: i64, factor: u32, max: i64) -> EscalationPolicy {
+        EscalationPolicy {
+            base: TimeDelta::minutes(base),
+            factor,
+            max: TimeDelta::minutes(max),
+            ..EscalationPolicy::default()
+        }
+    }
+
+    #[test]
+    fn ban_duration_grows_by_factor() {
+        let policy = policy(5, 2, 24 * 60);
+        assert_eq!(policy.ban_duration(1), TimeDelta::minutes(5));
+        assert_eq!(policy.ban_duration(2), TimeDelta::minutes(10));
+        assert_eq!(policy.ban_duration(3), TimeDelta::minutes(20));
+        assert_eq!(policy.ban_duration(5), TimeDelta::minutes(80));
+    }
+
+    #[test]
+    fn ban_duration_is_capped_at_max() {
+        let policy = policy(5, 3, 60);
+        assert_eq!(policy.ban_duration(3), TimeDelta::minutes(45));
+        assert_eq!(policy.ban_duration(4), TimeDelta::minutes(60));
+        // Overflowing multipliers saturate instead of wrapping
+        assert_eq!(policy.ban_duration(40), TimeDelta::minutes(60));
+        assert_eq!(policy.ban_duration(u32::MAX), TimeDelta::minutes(60));
+    }
+
+    #[test]
+    fn ban_duration_without_escalation() {
+        let policy = policy(5, 1, 60);
+        assert_eq!(policy.ban_duration(0), TimeDelta::minutes(5));
+        assert_eq!(policy.ban_duration(100), TimeDelta::minutes(5));
+    }
+
+    #[test]
+    fn escalation_validation() {
+        assert!(policy(5, 0, 60).validate().is_err());
+        assert!(policy(90, 2, 60).validate().is_err());
+        assert!(EscalationPolicy::default().validate().is_ok());
+    }
+
+    #[test]
+    fn durations_beyond_maximum_are_rejected() {
+        assert!(EscalationPolicy::default().validate().is_ok());
+        assert!(LockoutPolicy::default().validate().is_ok());
+        assert!(AuthConfig::default().validate().is_ok());
+
+        let escalation = EscalationPolicy {
+            max: TimeDelta::days(1_000_000_000),
+            ..EscalationPolicy::default()
+        };
+        assert!(escalation.validate().is_err());
+        let lockout = LockoutPolicy {
+            duration: Duration::from_secs(u64::MAX),
+            ..LockoutPolicy::default()
+        };
+        assert!(lockout.validate().is_err());
+        let rate_limits = RateLimits {
+            mute_duration: Duration::from_secs(1_000_000_000 * 24 * 60 * 60),
+            ..RateLimits::default()
+        };
+        assert!(rate_limits.validate().is_err());
+        let auth = AuthConfig {
+            rotation_grace: TimeDelta::MAX,
+            ..AuthConfig::default()
+        };
+        assert!(auth.validate().is_err());
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use getrandom::getrandom;
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::StaticSecret;
+
+use crate::utils::{load_toml, save_toml, write_private};
+
+/// Size of X25519 keys in bytes
+pub const KEY_LENGTH: usize = 32;
+
+/// Size of XChaCha20-Poly1305 nonces in bytes
+const NONCE_LENGTH: usize = 24;
+
+/// Context binding keys derived from shared secrets to direct messages
+const KDF_INFO: &[u8] = b"chat e2e direct message v1";
+
+/// Decode hex string into `N` bytes
+fn decode_hex<const N: usize>(s: &str) -> Result<[u8; N]> {
+    if !s.is_ascii() || s.len() != N * 2 {
+        bail!("Expected {len} hex characters", len = N * 2)
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(bytes)
+}
+
+/// Encode bytes as lowercase hex string
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// X25519 public key peers encrypt direct messages with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicKey(pub [u8; KEY_LENGTH]);
+
+impl PublicKey {
+    /// Short SHA-256 fingerprint for users to compare out of band
+    pub fn fingerprint(&self) -> String {
+        Sha256::digest(self.0)[..16]
+            .chunks(2)
+            .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", encode_hex(&self.0))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        decode_hex(s).map(PublicKey).context("Invalid public key")
+    }
+}
+
+/// Direct message encrypted by the sender for a single peer, opaque to the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedPayload {
+    pub nonce: Vec<u8>,
+    /// Text encrypted with XChaCha20-Poly1305, followed by its authentication tag
+    pub ciphertext: Vec<u8>,
+}
+
+/// Key pair held by a client, the secret never leaving it.
+/// Direct messages are encrypted with a key derived from the X25519 shared secret of
+/// sender and recipient, authenticating the sender at the same time.
+pub struct KeyPair {
+    secret: StaticSecret,
+    pub public: PublicKey,
+}
+
+impl std::fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KeyPair({})", self.public.fingerprint())
+    }
+}
+
+impl KeyPair {
+    /// Generate new random key pair
+    pub fn generate() -> Result<Self> {
+        let mut secret = [0; KEY_LENGTH];
+        getrandom(&mut secret).map_err(|e| anyhow!("Unable to generate key: {e}"))?;
+        Ok(Self::from_secret(secret))
+    }
+
+    fn from_secret(secret: [u8; KEY_LENGTH]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey(x25519_dalek::PublicKey::from(&secret).to_bytes());
+        Self { secret, public }
+    }
+
+    /// Load key pair from file, generating and saving a new one if it does not exist yet
+    pub fn load_or_generate(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(content) => decode_hex(content.trim())
+                .map(Self::from_secret)
+                .with_context(|| format!("Invalid key file {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key_pair = Self::generate()?;
+                let secret = encode_hex(key_pair.secret.as_bytes());
+                write_private(path, &format!("{secret}\n"))
+                    .with_context(|| format!("Unable to save key to {}", path.display()))?;
+                log::info!("Generated encryption key, saved to {}", path.display());
+                Ok(key_pair)
+            }
+            Err(e) => Err(e).with_context(|| format!("Unable to read key file {}", path.display())),
+        }
+    }
+
+    /// Cipher shared with peer
+    fn cipher(&self, peer: &PublicKey) -> Result<XChaCha20Poly1305> {
+        let shared = self
+            .secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(peer.0));
+        // Low order points would make the secret known to anyone
+        if !shared.was_contributory() {
+            bail!("Invalid peer key")
+        }
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(KDF_INFO, &mut key)
+            .map_err(|_| anyhow!("Unable to derive message key"))?;
+        Ok(XChaCha20Poly1305::new(&key.into()))
+    }
+
+    /// Encrypt text for recipient
+    pub fn encrypt(&self, recipient: &PublicKey, text: &str) -> Result<EncryptedPayload> {
+        let mut nonce = [0; NONCE_LENGTH];
+        getrandom(&mut nonce).map_err(|e| anyhow!("Unable to generate nonce: {e}"))?;
+        // Bind sender and recipient so the server cannot reflect or redirect messages
+        let aad = [self.public.0, recipient.0].concat();
+        let ciphertext = self
+            .cipher(recipient)?
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: text.as_bytes(),
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("Unable to encrypt message"))?;
+        Ok(EncryptedPayload {
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    /// Decrypt text sent by sender, failing if it was not encrypted by the sender key
+    /// or was tampered with
+    pub fn decrypt(&self, sender: &PublicKey, payload: &EncryptedPayload) -> Result<String> {
+        if payload.nonce.len() != NONCE_LENGTH {
+            bail!("Invalid nonce length {len}", len = payload.nonce.len())
+        }
+        let aad = [sender.0, self.public.0].concat();
+        let text = self
+            .cipher(sender)?
+            .decrypt(
+                XNonce::from_slice(&payload.nonce),
+                Payload {
+                    msg: &payload.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("Message does not match the sender key"))?;
+        String::from_utf8(text).context("Decrypted message is not valid UTF-8")
+    }
+}
+
+/// Result of checking the key of a peer against the known keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCheck {
+    /// First key seen for the name
+    New,
+    /// Same key as before
+    Known,
+    /// Key differs from the one seen before
+    Changed { previous: PublicKey },
+}
+
+/// Keys of peers by nickname, trusted on first use and saved to disk on every change
+#[derive(Debug)]
+pub struct KnownKeys {
+    /// File backing the keys
+    path: PathBuf,
+    /// Keys by lowercase nickname
+    keys: BTreeMap<String, PublicKey>,
+}
+
+impl KnownKeys {
+    /// Load known keys from file, starting empty if it does not exist yet
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let keys: BTreeMap<String, String> = load_toml(path, "known keys")?.unwrap_or_default();
+        let keys = keys
+            .into_iter()
+            .map(|(name, key)| Ok((name, key.parse()?)))
+            .collect::<Result<_>>()
+            .with_context(|| format!("Invalid known keys file {}", path.display()))?;
+        Ok(Self {
+            path: path.to_owned(),
+            keys,
+        })
+    }
+
+    /// Write keys to their file
+    fn save(&self) -> Result<()> {
+        let keys: BTreeMap<&String, String> = self
+            .keys
+            .iter()
+            .map(|(name, key)| (name, key.to_string()))
+            .collect();
+        save_toml(&self.path, "known keys", &keys)
+    }
+
+    /// Compare key of peer with the one seen before, remembering it from now on
+    pub fn check(&mut self, nickname: &str, key: PublicKey) -> Result<KeyCheck> {
+        let check = match self.keys.insert(nickname.to_lowercase(), key) {
+            None => KeyCheck::New,
+            Some(previous) if previous == key => return Ok(KeyCheck::Known),
+            Some(previous) => KeyCheck::Changed { previous },
+        };
+        self.save()?;
+        Ok(check)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let alice = KeyPair::generate().unwrap();
+        let bob = KeyPair::generate().unwrap();
+        let payload = alice.encrypt(&bob.public, "hello bob").unwrap();
+        assert_eq!(bob.decrypt(&alice.public, &payload).unwrap(), "hello bob");
+    }
+
+    #[test]
+    fn tampered_payload_is_rejected() {
+        let alice = KeyPair::generate().unwrap();
+        let bob = KeyPair::generate().unwrap();
+        let payload = alice.encrypt(&bob.public, "hello bob").unwrap();
+
+        let mut ciphertext = payload.clone();
+        ciphertext.ciphertext[0] ^= 1;
+        assert!(bob.decrypt(&alice.public, &ciphertext).is_err());
+
+        let mut nonce = payload.clone();
+        nonce.nonce[0] ^= 1;
+        assert!(bob.decrypt(&alice.public, &nonce).is_err());
+
+        let mut short = payload;
+        short.nonce.pop();
+        assert!(bob.decrypt(&alice.public, &short).is_err());
+    }
+
+    #[test]
+    fn wrong_keys_are_rejected() {
+        let alice = KeyPair::generate().unwrap();
+        let bob = KeyPair::generate().unwrap();
+        let mallory = KeyPair::generate().unwrap();
+        let payload = alice.encrypt(&bob.public, "hello bob").unwrap();
+        // Claimed sender differs, or the message was redirected or reflected
+        assert!(bob.decrypt(&mallory.public, &payload).is_err());
+        assert!(mallory.decrypt(&alice.public, &payload).is_err());
+        assert!(alice.decrypt(&bob.public, &payload).is_err());
+    }
+
+    #[test]
+    fn low_order_key_is_rejected() {
+        let alice = KeyPair::generate().unwrap();
+        assert!(alice.encrypt(&PublicKey([0; KEY_LENGTH]), "hello").is_err());
+    }
+
+    #[test]
+    fn public_key_text_round_trip() {
+        let key = KeyPair::generate().unwrap().public;
+        assert_eq!(key.to_string().parse::<PublicKey>().unwrap(), key);
+        assert!("not a key".parse::<PublicKey>().is_err());
+    }
+
+    #[test]
+    fn known_keys_detect_changes() {
+        let path =
+            std::env::temp_dir().join(format!("known-keys-{pid}.toml", pid = std::process::id()));
+        let _ = fs::remove_file(&path);
+        let first = KeyPair::generate().unwrap().public;
+        let second = KeyPair::generate().unwrap().public;
+
+        let mut known = KnownKeys::load(&path).unwrap();
+        assert_eq!(known.check("Alice", first).unwrap(), KeyCheck::New);
+        assert_eq!(known.check("alice", first).unwrap(), KeyCheck::Known);
+
+        let mut known = KnownKeys::load(&path).unwrap();
+        assert_eq!(
+            known.check("alice", second).unwrap(),
+            KeyCheck::Changed { previous: first }
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
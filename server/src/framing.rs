@@ -0,0 +1,234 @@
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the frame header holding the payload length
+pub const HEADER_SIZE: usize = 4;
+
+/// Maximum size of a frame payload in bytes
+pub const MAX_FRAME_SIZE: usize = 64 * 1024; // 64kb
+
+/// Errors while reading or writing frames
+#[derive(Debug)]
+pub enum FrameError {
+    /// Frame payload exceeds `MAX_FRAME_SIZE`
+    TooLarge(usize),
+    /// Underlying IO error
+    Io(io::Error),
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::TooLarge(size) => write!(
+                f,
+                "Frame of {size} bytes exceeds maximum frame size of {MAX_FRAME_SIZE} bytes"
+            ),
+            FrameError::Io(e) => write!(f, "IO error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::TooLarge(_) => None,
+            FrameError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+/// Parse payload length from frame header
+fn payload_length(header: [u8; HEADER_SIZE]) -> Result<usize, FrameError> {
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge(len));
+    }
+    Ok(len)
+}
+
+/// Build frame (header followed by payload) from payload
+pub fn encode_frame(payload: &[u8]) -> Result<Vec<u8>, FrameError> {
+    let len = payload.len();
+    if len > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge(len));
+    }
+    let mut frame = Vec::with_capacity(HEADER_SIZE + len);
+    frame.extend_from_slice(&(len as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Write payload as a single frame
+pub fn write_frame(mut writer: impl Write, payload: &[u8]) -> Result<(), FrameError> {
+    // Write header and payload at once so concurrent writers do not interleave
+    writer.write_all(&encode_frame(payload)?)?;
+    Ok(())
+}
+
+/// Read a whole frame payload, blocking until it is complete.
+/// Returns `None` if the stream ends at a frame boundary.
+pub fn read_frame(mut reader: impl Read) -> Result<Option<Vec<u8>>, FrameError> {
+    let mut header = [0; HEADER_SIZE];
+    let mut filled = 0;
+    while filled < HEADER_SIZE {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let mut payload = vec![0; payload_length(header)?];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Write payload as a single frame to an async writer
+pub async fn write_frame_async(
+    writer: &mut (impl AsyncWrite + Unpin),
+    payload: &[u8],
+) -> Result<(), FrameError> {
+    writer.write_all(&encode_frame(payload)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read a whole frame payload from an async reader.
+/// Returns `None` if the stream ends at a frame boundary.
+pub async fn read_frame_async(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<Vec<u8>>, FrameError> {
+    let mut header = [0; HEADER_SIZE];
+    let mut filled = 0;
+    while filled < HEADER_SIZE {
+        let read = match reader.read(&mut header[filled..]).await {
+            // TLS peers closing without notice end the stream all the same
+            Err(e) if filled == 0 && e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            read => read?,
+        };
+        match read {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            n => filled += n,
+        }
+    }
+
+    let mut payload = vec![0; payload_length(header)?];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+/// Reassembles frames from partial reads of a stream
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    /// Bytes received but not yet consumed as a frame
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    /// New empty decoder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append bytes read from the stream
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Pop next complete frame payload, if any
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let Some(header) = self.buffer.first_chunk::<HEADER_SIZE>() else {
+            return Ok(None);
+        };
+        let frame_end = HEADER_SIZE + payload_length(*header)?;
+        if self.buffer.len() < frame_end {
+            return Ok(None);
+        }
+        let payload = self.buffer[HEADER_SIZE..frame_end].to_vec();
+        self.buffer.drain(..frame_end);
+        Ok(Some(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoder_reassembles_partial_frames() {
+        let mut decoder = FrameDecoder::new();
+        let frame = encode_frame(b"hello").unwrap();
+
+        decoder.extend(&frame[..2]);
+        assert!(decoder.next_frame().unwrap().is_none());
+        decoder.extend(&frame[2..6]);
+        assert!(decoder.next_frame().unwrap().is_none());
+        decoder.extend(&frame[6..]);
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"hello");
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn decoder_splits_coalesced_frames() {
+        let mut decoder = FrameDecoder::new();
+        let mut bytes = encode_frame(b"first").unwrap();
+        bytes.extend(encode_frame(b"").unwrap());
+        bytes.extend(encode_frame(b"second").unwrap());
+        bytes.extend(&encode_frame(b"third").unwrap()[..3]);
+        decoder.extend(&bytes);
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"first");
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"");
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"second");
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn decoder_rejects_oversized_frame_from_header() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(FrameError::TooLarge(size)) if size == MAX_FRAME_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn encode_accepts_maximum_size_only() {
+        assert!(encode_frame(&vec![0; MAX_FRAME_SIZE]).is_ok());
+        assert!(matches!(
+            encode_frame(&vec![0; MAX_FRAME_SIZE + 1]),
+            Err(FrameError::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn read_frame_distinguishes_clean_and_truncated_end() {
+        let frame = encode_frame(b"payload").unwrap();
+        let mut reader = frame.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"payload");
+        assert!(read_frame(&mut reader).unwrap().is_none());
+
+        assert!(matches!(
+            read_frame(&frame[..2]),
+            Err(FrameError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+        assert!(matches!(
+            read_frame(&frame[..frame.len() - 1]),
+            Err(FrameError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+}
@@ -1,14 +1,59 @@
-/// Server thread
+/// Server task
 pub mod server;
 
-/// Client thread
+/// Client task
 pub mod client;
 
-/// Locally sent requests from client threads to server thread
+/// Locally sent requests from client tasks to server task
 pub mod requests;
 
-/// Messages exchange remotely between remote client and local client thread
+/// Messages exchange remotely between remote client and local client task
 pub mod messages;
 
+/// Length-prefixed framing of wire messages
+pub mod framing;
+
+/// Plain and TLS connections
+pub mod transport;
+
+/// Client sessions and their identities
+pub mod session;
+
+/// Access tokens
+pub mod auth;
+
+/// End-to-end encryption of direct messages
+pub mod e2e;
+
+/// Ed25519-signed login tokens verified offline
+pub mod signed_tokens;
+
+/// Registered user accounts
+pub mod accounts;
+
+/// Persistent ban list
+pub mod bans;
+
+/// Mutes outliving client sessions
+pub mod mutes;
+
+/// Brute-force protection of authentication
+pub mod lockout;
+
+/// Slash commands run by the server
+pub mod commands;
+
+/// Rate limiting of client traffic
+pub mod ratelimit;
+
+/// Chat rooms
+pub mod rooms;
+
+/// Per-client queues of outgoing messages
+pub mod outbound;
+
+/// Server configuration
+pub mod config;
+
 /// Utilities
 pub mod utils;
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::config::LockoutPolicy;
+
+/// Recent failed authentication attempts of an address or account
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    /// Failures since the address was last locked out
+    count: u32,
+    last_failure: Instant,
+    /// Time the current lockout ends, if any
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    /// Failures within the policy window, older ones being forgotten
+    fn recent(&self, policy: &LockoutPolicy, now: Instant) -> u32 {
+        if now.duration_since(self.last_failure) > policy.window {
+            0
+        } else {
+            self.count
+        }
+    }
+
+    /// Time left until the lockout ends
+    fn locked_for(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .filter(|locked_until| *locked_until > now)
+            .map(|locked_until| locked_until.duration_since(now))
+    }
+}
+
+/// Outcome of a failed authentication attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Address may try again after the given wait
+    Delayed { failures: u32, delay: Duration },
+    /// Address reached the maximum failures and is locked out
+    LockedOut { failures: u32, duration: Duration },
+}
+
+/// Failed authentication attempts by address, or by account for passwords, shared by
+/// all client tasks so reconnecting does not reset them
+#[derive(Debug, Clone)]
+pub struct AuthFailures<K = IpAddr>(Arc<Mutex<HashMap<K, Failures>>>);
+
+impl<K: Eq + Hash> Default for AuthFailures<K> {
+    fn default() -> Self {
+        Self(Arc::default())
+    }
+}
+
+impl<K: Eq + Hash> AuthFailures<K> {
+    /// Tracker without any failures
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn failures(&self) -> MutexGuard<'_, HashMap<K, Failures>> {
+        self.0
+            .lock()
+            .expect("Authentication failures lock poisoned")
+    }
+
+    /// Time left until locked out address or account may authenticate again
+    pub fn locked_for(&self, key: &K) -> Option<Duration> {
+        let now = Instant::now();
+        self.failures().get(key)?.locked_for(now)
+    }
+
+    /// Wait before handling the next attempt of address or account
+    pub fn delay(&self, key: &K, policy: &LockoutPolicy) -> Duration {
+        let now = Instant::now();
+        let failures = self
+            .failures()
+            .get(key)
+            .map_or(0, |failures| failures.recent(policy, now));
+        policy.delay(failures)
+    }
+
+    /// Record failed attempt of address or account, locking it out once it reaches
+    /// the maximum failures
+    pub fn record(&self, key: K, policy: &LockoutPolicy) -> Failure {
+        let now = Instant::now();
+        let mut failures = self.failures();
+        // Forget addresses without recent failures
+        failures.retain(|_, failures| {
+            failures.recent(policy, now) > 0 || failures.locked_for(now).is_some()
+        });
+        let entry = failures.entry(key).or_insert(Failures {
+            count: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        entry.count = entry.recent(policy, now).saturating_add(1);
+        entry.last_failure = now;
+        let count = entry.count;
+        if count >= policy.max_failures {
+            // Start over once the lockout ends
+            entry.count = 0;
+            entry.locked_until = Some(now + policy.duration);
+            Failure::LockedOut {
+                failures: count,
+                duration: policy.duration,
+            }
+        } else {
+            Failure::Delayed {
+                failures: count,
+                delay: policy.delay(count),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+
+    fn policy() -> LockoutPolicy {
+        LockoutPolicy {
+            max_failures: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            duration: Duration::from_secs(60),
+            window: Duration::from_secs(600),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_delay_then_lock_out() {
+        let failures = AuthFailures::new();
+        let (policy, key) = (policy(), "alice".to_owned());
+        assert_eq!(failures.delay(&key, &policy), Duration::ZERO);
+        assert_eq!(
+            failures.record(key.clone(), &policy),
+            Failure::Delayed {
+                failures: 1,
+                delay: Duration::from_secs(1)
+            }
+        );
+        assert_eq!(
+            failures.record(key.clone(), &policy),
+            Failure::Delayed {
+                failures: 2,
+                delay: Duration::from_secs(2)
+            }
+        );
+        assert_eq!(failures.delay(&key, &policy), Duration::from_secs(2));
+        assert_eq!(
+            failures.record(key.clone(), &policy),
+            Failure::LockedOut {
+                failures: 3,
+                duration: Duration::from_secs(60)
+            }
+        );
+        assert_eq!(failures.locked_for(&key), Some(Duration::from_secs(60)));
+        assert_eq!(failures.locked_for(&"bob".to_owned()), None);
+
+        time::advance(Duration::from_secs(61)).await;
+        assert_eq!(failures.locked_for(&key), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_are_forgotten_after_window() {
+        let failures: AuthFailures = AuthFailures::new();
+        let (policy, ip) = (policy(), "192.0.2.1".parse().unwrap());
+        failures.record(ip, &policy);
+        failures.record(ip, &policy);
+        time::advance(Duration::from_secs(601)).await;
+        assert_eq!(failures.delay(&ip, &policy), Duration::ZERO);
+        assert!(matches!(
+            failures.record(ip, &policy),
+            Failure::Delayed { failures: 1, .. }
+        ));
+    }
+}
@@ -1,9 +1,156 @@
-use std::io::{self, Read, Write};
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+};
 
+use anyhow::{Context, Result};
 use chrono::Utc;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::requests::BanReason;
+use crate::{
+    bans::Ban,
+    e2e::{EncryptedPayload, PublicKey},
+    framing,
+    requests::BanReason,
+    session::{Role, SessionId},
+};
+
+/// Message exchanged over the wire as a CBOR payload inside a length-prefixed frame
+pub trait WireMessage: Serialize + DeserializeOwned {
+    /// Serialize message into frame payload
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        ciborium::into_writer(self, &mut payload).context("Unable to serialize message")?;
+        Ok(payload)
+    }
+
+    /// Deserialize message from frame payload
+    fn decode(payload: &[u8]) -> Result<Self> {
+        ciborium::from_reader(payload).context("Unable to deserialize message")
+    }
+
+    /// Write message as a single frame
+    fn write_to(&self, writer: impl Write) -> Result<()> {
+        framing::write_frame(writer, &self.encode()?).context("Unable to write frame")
+    }
+
+    /// Read next message, blocking until a whole frame arrives.
+    /// Returns `None` if the stream was closed.
+    fn read_from(reader: impl Read) -> Result<Option<Self>> {
+        match framing::read_frame(reader)? {
+            None => Ok(None),
+            Some(payload) => Self::decode(&payload).map(Some),
+        }
+    }
+}
+
+impl WireMessage for MessageToClient {}
+
+impl WireMessage for MessageToServer {}
+
+impl WireMessage for Hello {}
+
+impl WireMessage for HelloReply {}
+
+/// Current version of the wire protocol, bumped once per release changing the wire format
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version still supported, raised only when dropping support for it
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features negotiated during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Capability {
+    Compression,
+    Rooms,
+    Attachments,
+    /// End-to-end encrypted direct messages
+    Encryption,
+    /// Capability unknown to this build
+    #[serde(other)]
+    Unknown,
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Capability::Compression => "Compression",
+                Capability::Rooms => "Rooms",
+                Capability::Attachments => "Attachments",
+                Capability::Encryption => "Encryption",
+                Capability::Unknown => "Unknown",
+            }
+        )
+    }
+}
+
+/// First message sent by remote client announcing its protocol support
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    /// Newest protocol version supported
+    pub version: u32,
+    /// Oldest protocol version supported
+    pub min_version: u32,
+    /// Capabilities supported
+    pub capabilities: Vec<Capability>,
+}
+
+impl Hello {
+    /// Hello announcing the protocol support of this build
+    pub fn new(capabilities: Vec<Capability>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+}
+
+/// Server response to the remote client `Hello`
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HelloReply {
+    /// Connection accepted using the negotiated version and capabilities
+    Accepted {
+        version: u32,
+        capabilities: Vec<Capability>,
+    },
+    /// Connection refused due to incompatible protocol versions
+    Rejected {
+        version: u32,
+        min_version: u32,
+        reason: String,
+    },
+}
+
+impl HelloReply {
+    /// Negotiate highest common protocol version and shared capabilities
+    pub fn negotiate(hello: &Hello, supported_capabilities: &[Capability]) -> Self {
+        let version = hello.version.min(PROTOCOL_VERSION);
+        if version < hello.min_version.max(MIN_PROTOCOL_VERSION) {
+            return Self::Rejected {
+                version: PROTOCOL_VERSION,
+                min_version: MIN_PROTOCOL_VERSION,
+                reason: format!(
+                    "Client supports protocol versions {min}-{max}",
+                    min = hello.min_version,
+                    max = hello.version
+                ),
+            };
+        }
+        Self::Accepted {
+            version,
+            capabilities: hello
+                .capabilities
+                .iter()
+                .copied()
+                .filter(|capability| supported_capabilities.contains(capability))
+                .collect(),
+        }
+    }
+}
 
 /// Message to be sent to remote client
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,13 +167,137 @@ impl MessageToClient {
             author: content,
         }
     }
+}
+
+/// Message sent from remote client to the server
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MessageToServer {
+    /// Answer authentication challenge with the HMAC-SHA256 of its nonce keyed by the
+    /// access token, optionally choosing a nickname
+    Auth {
+        response: Vec<u8>,
+        nickname: Option<String>,
+    },
+    /// Answer authentication challenge with a signed token, which names the client itself.
+    /// The token is a bearer credential, accepted over TLS only.
+    AuthSigned { token: SignedToken },
+    /// Text message to be broadcast to peers
+    Text(String),
+    /// Slash command with its raw argument string, parsed by the server
+    Command { name: String, args: String },
+    /// Check if the connection is alive
+    Ping,
+    /// Create account and log into it
+    Register {
+        username: String,
+        password: Password,
+    },
+    /// Log into account, binding it to the session
+    Login {
+        username: String,
+        password: Password,
+    },
+    /// Change password of the account logged into
+    ChangePassword {
+        old_password: Password,
+        new_password: Password,
+    },
+    /// Publish key peers encrypt direct messages to you with
+    PublishKey(PublicKey),
+    /// Direct message encrypted end-to-end, routed without the server reading it
+    Encrypted {
+        recipient: Recipient,
+        payload: EncryptedPayload,
+    },
+}
+
+/// Signed token sent as is over TLS, hidden from logs
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SignedToken(pub String);
+
+impl std::fmt::Debug for SignedToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SignedToken(***)")
+    }
+}
+
+/// Password sent in clear over the connection, hidden from logs
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Password(pub String);
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Password(***)")
+    }
+}
+
+impl MessageToServer {
+    /// Parse user input into message, treating lines starting with `/` as commands.
+    /// Account commands become their own messages so passwords never travel as command arguments.
+    pub fn from_input(input: &str) -> Option<Self> {
+        match input.strip_prefix('/') {
+            Some(command) => {
+                let command = command.trim_start();
+                let (name, args) = command
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((command, ""));
+                if name.is_empty() {
+                    return None;
+                }
+                let args = args.trim();
+                // Missing arguments are left empty for the server to reject
+                let (first, rest) = args
+                    .split_once(char::is_whitespace)
+                    .map_or((args, ""), |(first, rest)| (first, rest.trim()));
+                Some(match name {
+                    "register" => Self::Register {
+                        username: first.to_owned(),
+                        password: Password(rest.to_owned()),
+                    },
+                    "login" => Self::Login {
+                        username: first.to_owned(),
+                        password: Password(rest.to_owned()),
+                    },
+                    "passwd" => Self::ChangePassword {
+                        old_password: Password(first.to_owned()),
+                        new_password: Password(rest.to_owned()),
+                    },
+                    _ => Self::Command {
+                        name: name.to_owned(),
+                        args: args.to_owned(),
+                    },
+                })
+            }
+            None => Some(Self::Text(input.to_owned())),
+        }
+    }
+}
 
-    pub fn write_to(&self, writer: impl Write) -> Result<(), ciborium::ser::Error<io::Error>> {
-        ciborium::into_writer(self, writer)
+/// Peer addressed by a direct message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Recipient {
+    Id(SessionId),
+    Nickname(String),
+}
+
+impl Recipient {
+    /// Parse session ID (`#3` or `3`) or nickname
+    pub fn parse(s: &str) -> Self {
+        match s.strip_prefix('#').unwrap_or(s).parse() {
+            Ok(id) => Self::Id(SessionId(id)),
+            Err(_) => Self::Nickname(s.to_owned()),
+        }
     }
+}
 
-    pub fn read_from(reader: impl Read) -> Result<Self, ciborium::de::Error<io::Error>> {
-        ciborium::from_reader(reader)
+impl Display for Recipient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Recipient::Id(id) => write!(f, "#{id}"),
+            Recipient::Nickname(nickname) => write!(f, "{nickname}"),
+        }
     }
 }
 
@@ -34,20 +305,162 @@ impl MessageToClient {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum MessageAuthor {
     Server(ServerMessage),
-    Peer { id: usize, content: PeerMessage },
+    Peer {
+        id: SessionId,
+        /// Display name of the peer
+        name: String,
+        content: PeerMessage,
+    },
 }
 
 /// Messages the server
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerMessage {
-    Ban(BanReason),
+    /// Prove you know an access token by answering with `MessageToServer::Auth`
+    AuthChallenge {
+        nonce: Vec<u8>,
+    },
+    /// Your client certificate identifies you by the given name, no token needed
+    CertificateAccepted {
+        identity: String,
+    },
+    /// You are banned for the given number of seconds, or permanently
+    Banned {
+        reason: BanReason,
+        remaining_secs: Option<i64>,
+    },
+    /// You were disconnected by a moderator
+    Kicked {
+        reason: Option<String>,
+    },
+    /// You cannot send messages for the given number of seconds
+    Muted {
+        remaining_secs: i64,
+        reason: Option<String>,
+    },
+    /// You can send messages again
+    Unmuted,
+    /// Your privileges changed
+    RoleChanged(Role),
     Text(String),
+    Pong,
+    /// Message of the day
+    Motd(String),
+    /// You are logged into an account
+    LoggedIn {
+        username: String,
+        role: Role,
+    },
+    /// Connection accepted with the given session ID and display name
+    Connected {
+        id: SessionId,
+        name: String,
+    },
+    /// Peer published the key direct messages to it are encrypted with
+    PeerKey {
+        id: SessionId,
+        nickname: Option<String>,
+        key: PublicKey,
+    },
+    /// Peer changed its display name
+    Renamed {
+        id: SessionId,
+        old: String,
+        new: String,
+    },
+    /// Request could not be fulfilled
+    Error(String),
+    /// You are now in the given room
+    JoinedRoom(String),
+    /// Peer entered your room
+    PeerJoined {
+        id: SessionId,
+        name: String,
+    },
+    /// Peer left your room
+    PeerLeft {
+        id: SessionId,
+        name: String,
+    },
+    /// Existing rooms
+    RoomList(Vec<RoomInfo>),
+    /// Bans in effect
+    BanList(Vec<Ban>),
+    /// Users in a room
+    UserList {
+        room: String,
+        users: Vec<UserInfo>,
+    },
+    /// Commands available to you
+    Help(Vec<CommandInfo>),
+    /// Slash command failed
+    CommandError {
+        command: String,
+        error: CommandError,
+    },
+}
+
+/// Reason a slash command failed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CommandError {
+    /// No such command
+    Unknown,
+    /// Arguments do not match the command usage
+    Usage(String),
+    /// Your role is not allowed to run the command
+    PermissionDenied,
+    /// Command needs a capability your client did not negotiate
+    MissingCapability(Capability),
+    /// Command ran but could not be fulfilled
+    Failed(String),
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Unknown => write!(f, "Unknown command"),
+            CommandError::Usage(usage) => write!(f, "Usage: {usage}"),
+            CommandError::PermissionDenied => write!(f, "Permission denied"),
+            CommandError::MissingCapability(capability) => {
+                write!(f, "Requires the {capability} capability")
+            }
+            CommandError::Failed(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+/// Summary of a slash command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandInfo {
+    pub name: String,
+    /// Usage line, e.g. `/msg <recipient> <text...>`
+    pub usage: String,
+    pub description: String,
+}
+
+/// Summary of a connected user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub id: SessionId,
+    pub name: String,
+}
+
+/// Summary of a chat room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    /// Number of clients in the room
+    pub members: usize,
 }
 
 /// Messages from a remote peer
 #[derive(Debug, Serialize, Deserialize)]
 pub enum PeerMessage {
     Text(String),
+    /// Text sent only to you
+    Direct(String),
+    /// Text sent only to you, encrypted end-to-end
+    Encrypted(EncryptedPayload),
 }
 
 pub struct ClientMessage {
@@ -63,3 +476,40 @@ impl ClientMessage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newer_client_downgrades_to_server_version() {
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 3,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: vec![Capability::Rooms, Capability::Unknown],
+        };
+        match HelloReply::negotiate(&hello, &[Capability::Rooms, Capability::Encryption]) {
+            HelloReply::Accepted {
+                version,
+                capabilities,
+            } => {
+                assert_eq!(version, PROTOCOL_VERSION);
+                assert_eq!(capabilities, vec![Capability::Rooms]);
+            }
+            reply => panic!("Expected accepted handshake, got {reply:?}"),
+        }
+    }
+
+    #[test]
+    fn client_without_common_version_is_rejected() {
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 2,
+            min_version: PROTOCOL_VERSION + 1,
+            capabilities: Vec::new(),
+        };
+        assert!(matches!(
+            HelloReply::negotiate(&hello, &[]),
+            HelloReply::Rejected { .. }
+        ));
+    }
+}
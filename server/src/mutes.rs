@@ -0,0 +1,140 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use chrono::{DateTime, Utc};
+
+use crate::messages::ServerMessage;
+
+/// Mute of an address or account
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mute {
+    /// Time the client may send messages again
+    pub until: DateTime<Utc>,
+    pub reason: Option<String>,
+}
+
+impl Mute {
+    /// Seconds left until the mute ends, if still in effect
+    pub fn remaining_secs(&self) -> Option<i64> {
+        let remaining = self.until.signed_duration_since(Utc::now()).num_seconds();
+        (remaining > 0).then_some(remaining)
+    }
+
+    /// Message letting the muted client know
+    pub fn to_message(&self) -> ServerMessage {
+        ServerMessage::Muted {
+            remaining_secs: self.remaining_secs().unwrap_or_default(),
+            reason: self.reason.clone(),
+        }
+    }
+}
+
+/// Mutes by address and account, outliving the sessions they were given to
+/// so reconnecting does not lift them
+#[derive(Debug, Default)]
+pub struct Mutes {
+    by_ip: HashMap<IpAddr, Mute>,
+    /// Mutes by lowercase username
+    by_account: HashMap<String, Mute>,
+}
+
+impl Mutes {
+    /// No one muted
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget mutes that ended
+    fn prune(&mut self) {
+        let now = Utc::now();
+        self.by_ip.retain(|_, mute| mute.until > now);
+        self.by_account.retain(|_, mute| mute.until > now);
+    }
+
+    /// Mute address, and account if any, keeping longer mutes already in effect
+    pub fn insert(&mut self, ip: IpAddr, account: Option<&str>, mute: Mute) {
+        self.prune();
+        let keep_longer = |current: &mut Mute| {
+            if current.until < mute.until {
+                *current = mute.clone();
+            }
+        };
+        self.by_ip
+            .entry(ip)
+            .and_modify(keep_longer)
+            .or_insert_with(|| mute.clone());
+        if let Some(account) = account {
+            self.by_account
+                .entry(account.to_lowercase())
+                .and_modify(keep_longer)
+                .or_insert_with(|| mute.clone());
+        }
+    }
+
+    /// Lift mutes of address and account. Returns whether any was in effect.
+    pub fn remove(&mut self, ip: IpAddr, account: Option<&str>) -> bool {
+        self.prune();
+        let by_ip = self.by_ip.remove(&ip).is_some();
+        let by_account = account
+            .and_then(|account| self.by_account.remove(&account.to_lowercase()))
+            .is_some();
+        by_ip || by_account
+    }
+
+    /// Longest mute in effect for address or account
+    pub fn find(&self, ip: IpAddr, account: Option<&str>) -> Option<&Mute> {
+        let by_account = account.and_then(|account| self.by_account.get(&account.to_lowercase()));
+        self.by_ip
+            .get(&ip)
+            .into_iter()
+            .chain(by_account)
+            .filter(|mute| mute.remaining_secs().is_some())
+            .max_by_key(|mute| mute.until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    fn mute(secs: i64) -> Mute {
+        Mute {
+            until: Utc::now() + TimeDelta::seconds(secs),
+            reason: None,
+        }
+    }
+
+    #[test]
+    fn mute_follows_address_and_account() {
+        let mut mutes = Mutes::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        mutes.insert(ip, Some("Alice"), mute(60));
+
+        assert!(mutes.find(ip, None).is_some());
+        assert!(mutes.find(other, Some("alice")).is_some());
+        assert!(mutes.find(other, Some("bob")).is_none());
+
+        assert!(mutes.remove(ip, Some("alice")));
+        assert!(mutes.find(other, Some("alice")).is_none());
+        assert!(!mutes.remove(ip, None));
+    }
+
+    #[test]
+    fn longer_mute_is_kept() {
+        let mut mutes = Mutes::new();
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        mutes.insert(ip, None, mute(600));
+        mutes.insert(ip, None, mute(60));
+        assert!(mutes.find(ip, None).unwrap().remaining_secs().unwrap() > 60);
+    }
+
+    #[test]
+    fn ended_mute_is_ignored() {
+        let mut mutes = Mutes::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        mutes.insert(ip, None, mute(-1));
+        assert!(mutes.find(ip, None).is_none());
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncWriteExt, WriteHalf},
+    sync::{watch, Notify},
+    time,
+};
+
+use crate::{
+    framing,
+    messages::{MessageAuthor, MessageToClient, ServerMessage, WireMessage},
+    transport::Transport,
+};
+
+/// Encoded frame ready to be written, shared between all recipients
+pub type Frame = Arc<[u8]>;
+
+/// Encode message to client into a frame
+pub fn encode_frame(message: &MessageToClient) -> Result<Frame> {
+    Ok(framing::encode_frame(&message.encode()?)?.into())
+}
+
+/// What to do when a client's outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued message to make room
+    DropOldest,
+    /// Close the connection
+    Disconnect,
+    /// Discard new messages and let the client know how many it missed once it catches up
+    MarkLagging,
+}
+
+/// Outcome of queuing a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enqueued {
+    /// Frame queued
+    Queued,
+    /// Frame queued after discarding the oldest one
+    DroppedOldest,
+    /// Frame discarded because the client is lagging
+    Lagging,
+    /// Queue overflowed and the connection is being closed
+    Disconnected,
+    /// Connection already closed
+    Closed,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    frames: VecDeque<Frame>,
+    /// Frames discarded since the client started lagging
+    missed: usize,
+}
+
+/// Bounded queue of frames waiting to be written to a client by its writer task
+#[derive(Debug)]
+pub struct Outbound {
+    queue: Mutex<Queue>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Wakes the writer task when frames are queued or the queue is closed
+    wake_writer: Notify,
+    /// Whether the connection is closed
+    closed: watch::Sender<bool>,
+}
+
+impl Outbound {
+    /// New empty queue
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            queue: Mutex::new(Queue::default()),
+            capacity,
+            policy,
+            wake_writer: Notify::new(),
+            closed: watch::Sender::new(false),
+        }
+    }
+
+    /// Whether the connection is closed
+    pub fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    /// Queue frame to be written, applying the overflow policy if the queue is full
+    pub fn push(&self, frame: Frame) -> Enqueued {
+        if self.is_closed() {
+            return Enqueued::Closed;
+        }
+        let mut queue = self.queue.lock().expect("Outbound queue lock poisoned");
+        let enqueued = if queue.frames.len() < self.capacity {
+            queue.frames.push_back(frame);
+            Enqueued::Queued
+        } else {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    queue.frames.pop_front();
+                    queue.frames.push_back(frame);
+                    Enqueued::DroppedOldest
+                }
+                OverflowPolicy::MarkLagging => {
+                    queue.missed += 1;
+                    Enqueued::Lagging
+                }
+                OverflowPolicy::Disconnect => {
+                    queue.frames.clear();
+                    drop(queue);
+                    self.close();
+                    return Enqueued::Disconnected;
+                }
+            }
+        };
+        drop(queue);
+        self.wake_writer.notify_one();
+        enqueued
+    }
+
+    /// Close the connection. Frames already queued are still written.
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+        self.wake_writer.notify_one();
+    }
+
+    /// Wait until the connection is closed
+    pub async fn closed(&self) {
+        let _ = self.closed.subscribe().wait_for(|closed| *closed).await;
+    }
+
+    /// Next frame to be written. Returns `None` once closed and drained.
+    async fn pop(&self) -> Option<Frame> {
+        loop {
+            {
+                let mut queue = self.queue.lock().expect("Outbound queue lock poisoned");
+                if let Some(frame) = queue.frames.pop_front() {
+                    return Some(frame);
+                }
+                // Client caught up, let it know what it missed
+                if queue.missed > 0 {
+                    let missed = std::mem::take(&mut queue.missed);
+                    let notice = MessageToClient::new(MessageAuthor::Server(ServerMessage::Text(
+                        format!("You fell behind and missed {missed} messages"),
+                    )));
+                    match encode_frame(&notice) {
+                        Ok(frame) => return Some(frame),
+                        Err(e) => log::error!("Unable to encode lag notice: {e}"),
+                    }
+                }
+                if self.is_closed() {
+                    return None;
+                }
+            }
+            self.wake_writer.notified().await;
+        }
+    }
+}
+
+/// Write queued frames to the remote stream until the queue is closed and drained
+pub async fn write_loop(
+    outbound: Arc<Outbound>,
+    mut writer: WriteHalf<Transport>,
+    addr: SocketAddr,
+    write_timeout: Duration,
+) {
+    while let Some(frame) = outbound.pop().await {
+        // Flush every frame so TLS records are not held back
+        let write = async {
+            writer.write_all(&frame).await?;
+            writer.flush().await
+        };
+        match time::timeout(write_timeout, write).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                log::error!("Unable to write to Client {addr}: {e}");
+                break;
+            }
+            Err(_) => {
+                log::warn!("Client {addr} is not accepting writes, closing connection");
+                break;
+            }
+        }
+    }
+    outbound.close();
+    log::debug!("Shutting down Client {addr} stream");
+    let _ = time::timeout(write_timeout, writer.shutdown()).await;
+}
@@ -0,0 +1,238 @@
+use std::{fmt::Display, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::config::{RateLimit, RateLimits};
+
+/// Token bucket refilling continuously up to its burst size
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    /// Tokens available, negative while throttled clients pay back debt
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// New full bucket
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Change limit, keeping tokens up to the new burst size
+    fn set_limit(&mut self, limit: RateLimit) {
+        self.refill();
+        self.limit = limit;
+        self.tokens = self.tokens.min(limit.burst as f64);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    /// Whether `cost` tokens are available. Costs above the burst size only need a full bucket.
+    fn has(&mut self, cost: f64) -> bool {
+        self.refill();
+        self.tokens >= cost.min(self.limit.burst as f64)
+    }
+
+    /// Take tokens, going into debt if there are not enough
+    fn take(&mut self, cost: f64) {
+        self.refill();
+        self.tokens -= cost;
+    }
+
+    /// Time until `cost` tokens are available
+    fn wait_time(&mut self, cost: f64) -> Duration {
+        self.refill();
+        let missing = cost.min(self.limit.burst as f64) - self.tokens;
+        if missing <= 0.0 || self.limit.per_second <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.limit.per_second)
+        }
+    }
+}
+
+/// Kind of incoming traffic, each limited separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traffic {
+    /// Chat messages
+    Message,
+    /// Slash commands
+    Command,
+    /// Anything else, only counted towards the byte limit
+    Other,
+}
+
+/// Limit exceeded by a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exceeded {
+    Messages,
+    Bytes,
+    Commands,
+}
+
+impl Display for Exceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Exceeded::Messages => "message rate limit",
+                Exceeded::Bytes => "byte rate limit",
+                Exceeded::Commands => "command rate limit",
+            }
+        )
+    }
+}
+
+/// Rate limiter of a single client
+#[derive(Debug)]
+pub struct RateLimiter {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    commands: TokenBucket,
+}
+
+impl RateLimiter {
+    /// New limiter with full buckets
+    pub fn new(limits: &RateLimits) -> Self {
+        Self {
+            messages: TokenBucket::new(limits.messages),
+            bytes: TokenBucket::new(limits.bytes),
+            commands: TokenBucket::new(limits.commands),
+        }
+    }
+
+    /// Apply new limits, keeping the traffic already accounted for
+    pub fn update(&mut self, limits: &RateLimits) {
+        self.messages.set_limit(limits.messages);
+        self.bytes.set_limit(limits.bytes);
+        self.commands.set_limit(limits.commands);
+    }
+
+    /// Bucket limiting the given kind of traffic, besides the byte bucket
+    fn bucket(&mut self, traffic: Traffic) -> Option<(&mut TokenBucket, Exceeded)> {
+        match traffic {
+            Traffic::Message => Some((&mut self.messages, Exceeded::Messages)),
+            Traffic::Command => Some((&mut self.commands, Exceeded::Commands)),
+            Traffic::Other => None,
+        }
+    }
+
+    /// Account for an incoming message of `size` bytes.
+    /// Nothing is taken if any of the limits would be exceeded.
+    pub fn check(&mut self, traffic: Traffic, size: usize) -> Result<(), Exceeded> {
+        if !self.bytes.has(size as f64) {
+            return Err(Exceeded::Bytes);
+        }
+        if let Some((bucket, exceeded)) = self.bucket(traffic) {
+            if !bucket.has(1.0) {
+                return Err(exceeded);
+            }
+            bucket.take(1.0);
+        }
+        self.bytes.take(size as f64);
+        Ok(())
+    }
+
+    /// Time until an incoming message of `size` bytes fits within the limits
+    pub fn wait_time(&mut self, traffic: Traffic, size: usize) -> Duration {
+        let bytes = self.bytes.wait_time(size as f64);
+        let other = self
+            .bucket(traffic)
+            .map_or(Duration::ZERO, |(bucket, _)| bucket.wait_time(1.0));
+        bytes.max(other)
+    }
+
+    /// Account for an incoming message regardless of the limits
+    pub fn take(&mut self, traffic: Traffic, size: usize) {
+        self.bytes.take(size as f64);
+        if let Some((bucket, _)) = self.bucket(traffic) {
+            bucket.take(1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+    use crate::config::Consequence;
+
+    fn limits(messages: u32, bytes: u32, commands: u32) -> RateLimits {
+        let limit = |burst| RateLimit {
+            burst,
+            per_second: burst as f64,
+        };
+        RateLimits {
+            messages: limit(messages),
+            bytes: limit(bytes),
+            commands: limit(commands),
+            consequence: Consequence::Throttle,
+            mute_duration: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills_over_time() {
+        let mut limiter = RateLimiter::new(&limits(2, 1000, 10));
+        assert_eq!(limiter.check(Traffic::Message, 10), Ok(()));
+        assert_eq!(limiter.check(Traffic::Message, 10), Ok(()));
+        assert_eq!(limiter.check(Traffic::Message, 10), Err(Exceeded::Messages));
+        assert_eq!(
+            limiter.wait_time(Traffic::Message, 10),
+            Duration::from_millis(500)
+        );
+
+        time::advance(Duration::from_millis(500)).await;
+        assert_eq!(limiter.check(Traffic::Message, 10), Ok(()));
+        assert_eq!(limiter.check(Traffic::Message, 10), Err(Exceeded::Messages));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refused_traffic_takes_nothing() {
+        let mut limiter = RateLimiter::new(&limits(10, 100, 1));
+        assert_eq!(limiter.check(Traffic::Message, 101), Ok(()));
+        assert_eq!(limiter.check(Traffic::Command, 1), Err(Exceeded::Bytes));
+        // The refused command did not use up the command bucket
+        time::advance(Duration::from_millis(100)).await;
+        assert_eq!(limiter.check(Traffic::Command, 1), Ok(()));
+        assert_eq!(limiter.check(Traffic::Command, 1), Err(Exceeded::Commands));
+        assert_eq!(limiter.check(Traffic::Other, 1), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forced_traffic_goes_into_debt() {
+        let mut limiter = RateLimiter::new(&limits(2, 1000, 10));
+        for _ in 0..4 {
+            limiter.take(Traffic::Message, 1);
+        }
+        // Two messages of debt and one to send take 1.5 seconds to refill
+        assert_eq!(
+            limiter.wait_time(Traffic::Message, 1),
+            Duration::from_millis(1500)
+        );
+        time::advance(Duration::from_millis(1400)).await;
+        assert_eq!(limiter.check(Traffic::Message, 1), Err(Exceeded::Messages));
+        time::advance(Duration::from_millis(200)).await;
+        assert_eq!(limiter.check(Traffic::Message, 1), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn update_caps_tokens_at_new_burst() {
+        let mut limiter = RateLimiter::new(&limits(10, 1000, 10));
+        limiter.update(&limits(1, 1000, 10));
+        assert_eq!(limiter.check(Traffic::Message, 1), Ok(()));
+        assert_eq!(limiter.check(Traffic::Message, 1), Err(Exceeded::Messages));
+    }
+}
@@ -1,12 +1,15 @@
-use std::{
-    fmt::Display,
-    net::{SocketAddr, TcpStream},
-    sync::Arc,
-};
+use std::{fmt::Display, net::SocketAddr, sync::Arc, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
-/// Messages sent locally from client thread to server
+use crate::{
+    e2e::{EncryptedPayload, PublicKey},
+    messages::{Capability, Recipient},
+    outbound::Outbound,
+    session::Role,
+};
+
+/// Messages sent locally from client task to server
 #[derive(Debug)]
 pub struct ClientRequest {
     /// Address of the client sending the request
@@ -26,13 +29,42 @@ impl Display for ClientRequest {
     }
 }
 
-/// Request from client thread to server
+/// Request from client task to server
 #[derive(Debug)]
 pub(crate) enum Request {
-    Connect(Arc<TcpStream>),
+    /// Connect client, handing its outbound queue to the server
+    Connect {
+        outbound: Arc<Outbound>,
+        nickname: Option<String>,
+        capabilities: Vec<Capability>,
+        role: Role,
+        /// Whether the connection is encrypted with TLS
+        tls: bool,
+    },
     Disconnet,
     Ban(BanReason),
+    /// Mute client for exceeding its rate limits
+    Mute(Duration),
+    /// Ban address of client for too many failed authentication attempts
+    Lockout(Duration),
     Broadcast(String),
+    /// Run slash command with its raw argument string
+    Command {
+        name: String,
+        args: String,
+    },
+    /// Bind account the client logged into to its session
+    Login {
+        username: String,
+        role: Role,
+    },
+    /// Share key peers encrypt direct messages to the client with
+    PublishKey(PublicKey),
+    /// Route end-to-end encrypted direct message
+    Encrypted {
+        recipient: Recipient,
+        payload: EncryptedPayload,
+    },
 }
 
 impl Display for Request {
@@ -41,28 +73,45 @@ impl Display for Request {
             f,
             "{}",
             match self {
-                Request::Connect(_) => "Connect Request".to_owned(),
+                Request::Connect { .. } => "Connect Request".to_owned(),
                 Request::Disconnet => "Disconnect Request".to_owned(),
                 Request::Ban(reason) => {
-                    "Ban Me for ".to_owned()
-                        + match reason {
-                            BanReason::Spamming => "Spamming",
-                            BanReason::_Other(reason) => reason,
-                        }
+                    format!("Ban Me for {reason}")
+                }
+                Request::Mute(duration) => {
+                    format!("Mute Me for {secs} seconds", secs = duration.as_secs())
+                }
+                Request::Lockout(duration) => {
+                    format!("Lock Me out for {secs} seconds", secs = duration.as_secs())
                 }
                 Request::Broadcast(text) => {
                     format!("Broadcast: {text}")
                 }
+                Request::Command { name, args } => {
+                    format!("Command: /{name} {args}")
+                }
+                Request::Login { username, .. } => {
+                    format!("Login as {username}")
+                }
+                Request::PublishKey(key) => {
+                    format!("Publish key {fingerprint}", fingerprint = key.fingerprint())
+                }
+                Request::Encrypted { recipient, .. } => {
+                    format!("Encrypted message to {recipient}")
+                }
             }
         )
     }
 }
 
 /// Reason for client to be banned from the server
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BanReason {
     Spamming,
-    _Other(String),
+    /// Too many failed authentication attempts
+    FailedLogins,
+    /// Reason given by a moderator
+    Other(String),
 }
 
 impl Display for BanReason {
@@ -72,7 +121,8 @@ impl Display for BanReason {
             "{}",
             match self {
                 BanReason::Spamming => "Spamming",
-                BanReason::_Other(reason) => reason,
+                BanReason::FailedLogins => "Too many failed login attempts",
+                BanReason::Other(reason) => reason,
             }
         )
     }
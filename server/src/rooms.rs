@@ -0,0 +1,106 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+};
+
+use anyhow::{bail, Result};
+
+use crate::messages::RoomInfo;
+
+/// Room every client joins on connection. It is never removed.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// Maximum room name length in characters
+pub const MAX_ROOM_NAME_LENGTH: usize = 32;
+
+/// Check room name length and characters
+pub fn validate_room_name(name: &str) -> Result<()> {
+    let len = name.chars().count();
+    if !(1..=MAX_ROOM_NAME_LENGTH).contains(&len) {
+        bail!("Room name must be between 1 and {MAX_ROOM_NAME_LENGTH} characters long")
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    {
+        bail!("Room name may only contain letters, digits, `_` and `-`")
+    }
+    Ok(())
+}
+
+/// Chat rooms and the clients in each of them
+#[derive(Debug)]
+pub struct Rooms {
+    rooms: HashMap<String, HashSet<SocketAddr>>,
+}
+
+impl Default for Rooms {
+    fn default() -> Self {
+        Self {
+            rooms: HashMap::from([(DEFAULT_ROOM.to_owned(), HashSet::new())]),
+        }
+    }
+}
+
+impl Rooms {
+    /// New set of rooms containing only the default room
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether room exists
+    pub fn exists(&self, name: &str) -> bool {
+        self.rooms.contains_key(name)
+    }
+
+    /// Create new empty room
+    pub fn create(&mut self, name: &str) -> Result<()> {
+        validate_room_name(name)?;
+        if self.exists(name) {
+            bail!("Room {name} already exists")
+        }
+        self.rooms.insert(name.to_owned(), HashSet::new());
+        Ok(())
+    }
+
+    /// Add client to existing room
+    pub fn join(&mut self, name: &str, addr: SocketAddr) -> Result<()> {
+        match self.rooms.get_mut(name) {
+            None => bail!("No such room {name}"),
+            Some(members) => {
+                members.insert(addr);
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove client from room, removing the room once empty
+    pub fn leave(&mut self, name: &str, addr: SocketAddr) {
+        if let Some(members) = self.rooms.get_mut(name) {
+            members.remove(&addr);
+            if members.is_empty() && name != DEFAULT_ROOM {
+                log::info!("Removing empty room {name}");
+                self.rooms.remove(name);
+            }
+        }
+    }
+
+    /// Clients in room
+    pub fn members(&self, name: &str) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.rooms.get(name).into_iter().flatten().copied()
+    }
+
+    /// Summary of every room, sorted by name
+    pub fn list(&self) -> Vec<RoomInfo> {
+        let mut rooms: Vec<RoomInfo> = self
+            .rooms
+            .iter()
+            .map(|(name, members)| RoomInfo {
+                name: name.clone(),
+                members: members.len(),
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+}
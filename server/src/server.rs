@@ -1,103 +1,123 @@
 use core::str;
-use std::{
-    collections::HashMap, fmt::Display, net::{self, IpAddr, SocketAddr, TcpStream}, sync::{mpsc::Receiver, Arc}
-};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use anyhow::{anyhow, bail, Context, Result};
-use chrono::{DateTime, TimeDelta, Utc};
-use getrandom::getrandom;
-
-use crate::{messages::{MessageAuthor, MessageToClient, PeerMessage, ServerMessage}, requests::{BanReason, ClientRequest, Request}};
-
-
-// TODO: Authentication
-// TODO: Fix vulnerability to `slow loris reader`
-
-/// Total a client remains banned
-const TOTAL_BAN_TIME: TimeDelta = TimeDelta::seconds(5 * 60);
-
-/// Server access token length in bytes
-pub const TOKEN_LENGTH: usize = 8;
-
-/// Server Access Token
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Token([u8; TOKEN_LENGTH]);
-
-impl Token {
-    /// Generate new random access token
-    fn generate() -> Result<Token> {
-        let mut buffer = [0; TOKEN_LENGTH];
-        getrandom(&mut buffer).map_err(|e| anyhow!("Unable to generate random token: {e}"))?;
-        Ok(Token(buffer))
-    }
-
-    /// Attempts to parse access token from hex representation string
-    pub(crate) fn from_str(s: &str) -> Result<Self> {
-        log::debug!("Token string: {s}");
-
-        if !s.is_ascii() {
-            bail!("Token string must be ASCII")
-        }
-        let str_len = s.len();
-        if str_len != (2 * TOKEN_LENGTH) {
-            bail!("Invalid token string length: {str_len}")
-        }
+use chrono::{TimeDelta, Utc};
+use ipnet::IpNet;
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    watch,
+};
 
-        let mut buffer = [0; TOKEN_LENGTH];
-        for (b, k) in buffer.iter_mut().zip((0..s.len()).step_by(2)) {
-            *b = u8::from_str_radix(&s[k..k + 2], 16)?;
-        }
-        Ok(Token(buffer))
-    }
-}
+use crate::{
+    accounts::Accounts,
+    auth::AccessTokens,
+    bans::{Ban, BanList},
+    commands::CommandRegistry,
+    config::{Config, ConfigSource},
+    e2e::{EncryptedPayload, PublicKey},
+    messages::{
+        Capability, CommandError, MessageAuthor, MessageToClient, PeerMessage, Recipient,
+        ServerMessage, UserInfo,
+    },
+    mutes::{Mute, Mutes},
+    outbound::{self, Enqueued, Outbound},
+    requests::{BanReason, ClientRequest, Request},
+    rooms::{Rooms, DEFAULT_ROOM},
+    session::{validate_nickname, IdAllocator, Role, Session},
+};
 
-impl Display for Token {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for b in self.0.iter() {
-            write!(f, "{b:02X}")?;
-        }
-        Ok(())
+/// Queue server message to client
+fn message_client(message: ServerMessage, outbound: &Outbound) -> Result<()> {
+    let frame = outbound::encode_frame(&MessageToClient::new(MessageAuthor::Server(message)))?;
+    match outbound.push(frame) {
+        Enqueued::Closed | Enqueued::Disconnected => bail!("Connection closed"),
+        _ => Ok(()),
     }
 }
 
-/// Send server message to client stream
-fn message_client(message: ServerMessage, stream: &TcpStream) -> Result<()> {
-    MessageToClient::new(MessageAuthor::Server(message)).write_to(stream).context("Unable to send message")
-}
-
-#[derive(Debug)]
-struct Client {
-    id: usize,
-    stream: Arc<TcpStream>,
-}
-
 #[derive(Debug)]
 pub struct Server {
-    receiver: Receiver<ClientRequest>,
-    access_token: Token,
-    ban_list: HashMap<IpAddr, DateTime<Utc>>,
-    clients: HashMap<SocketAddr, Client>,
+    receiver: UnboundedReceiver<ClientRequest>,
+    /// Configuration in effect, watched by client tasks
+    config: watch::Sender<Arc<Config>>,
+    /// Source the configuration is reloaded from
+    config_source: ConfigSource,
+    /// Requests to reload the configuration
+    reload_sender: UnboundedSender<()>,
+    reload_receiver: UnboundedReceiver<()>,
+    /// Tokens accepted from new connections
+    access_tokens: watch::Sender<Arc<AccessTokens>>,
+    /// Registered accounts, shared with client tasks
+    accounts: Accounts,
+    ban_list: BanList,
+    mutes: Mutes,
+    ids: IdAllocator,
+    clients: HashMap<SocketAddr, Session>,
+    rooms: Rooms,
+    commands: CommandRegistry,
 }
 
 impl Server {
-    /// Create new empty Server
-    pub fn new(receiver: Receiver<ClientRequest>) -> Result<Self> {
+    /// Create new Server running with the given configuration, reloaded from `config_source`
+    pub fn new(
+        receiver: UnboundedReceiver<ClientRequest>,
+        config: Config,
+        config_source: ConfigSource,
+    ) -> Result<Self> {
         log::trace!("Creating new Server");
 
-        // Generate access token
-        let access_token = Token::generate()?;
-        log::info!("Access token: {access_token}");
+        // Load bans from previous runs
+        let ban_list = BanList::load(&config.bans.file, config.bans.escalation)
+            .context("Unable to load ban list")?;
+
+        // Generate access tokens
+        let access_tokens = AccessTokens::load(&config.auth)?;
 
+        // Load registered accounts
+        let accounts = Accounts::load(&config.accounts.file).context("Unable to load accounts")?;
+
+        let (reload_sender, reload_receiver) = mpsc::unbounded_channel();
         Ok(Self {
             receiver,
-            access_token,
-            ban_list: HashMap::new(),
+            config: watch::Sender::new(Arc::new(config)),
+            config_source,
+            reload_sender,
+            reload_receiver,
+            access_tokens: watch::Sender::new(Arc::new(access_tokens)),
+            accounts,
+            ban_list,
+            mutes: Mutes::new(),
+            ids: IdAllocator::new(),
             clients: HashMap::new(),
+            rooms: Rooms::new(),
+            commands: CommandRegistry::builtin(),
         })
     }
 
-    pub fn access_token(&self) -> Token {
-        self.access_token
+    /// Configuration in effect, updated when reloaded
+    pub fn config(&self) -> watch::Receiver<Arc<Config>> {
+        self.config.subscribe()
+    }
+
+    /// Tokens accepted from new connections, updated when reloaded
+    pub fn access_tokens(&self) -> watch::Receiver<Arc<AccessTokens>> {
+        self.access_tokens.subscribe()
+    }
+
+    /// Registered accounts, shared with client tasks
+    pub fn accounts(&self) -> Accounts {
+        self.accounts.clone()
+    }
+
+    /// Channel to ask the server to reload its configuration
+    pub fn reload_trigger(&self) -> UnboundedSender<()> {
+        self.reload_sender.clone()
+    }
+
+    /// Commands clients can run
+    pub fn commands_mut(&mut self) -> &mut CommandRegistry {
+        &mut self.commands
     }
 
     /// Filter messages from banned IPs. Returns is banned boolean.
@@ -105,176 +125,1147 @@ impl Server {
         let addr = request.addr;
         let ip_addr = addr.ip();
         log::trace!("Checking IP {ip_addr} ban status");
-        if let Some(banned_at) = self.ban_list.get(&ip_addr) {
-            // Calculate ban time remaining
-            let remaining_secs = (*banned_at + TOTAL_BAN_TIME)
-                .signed_duration_since(Utc::now())
-                .num_seconds();
-            if remaining_secs > 0 {
-                log::debug!(
-                    "IP {ip_addr} is currently banned. Remaining time: {remaining_secs} seconds"
-                );
-                // Disconnect banned client if currently connected
-                if let Some(client) = self.clients.remove(&addr) {
-                    let _ =  message_client(ServerMessage::Text(format!(
-                            "You are currently banned\nRemaining time: {remaining_secs} seconds\n"
-                        )) , client.stream.as_ref());
-                } else {
-                    // Refuse Connect Request
-                    if let Request::Connect(stream) = &request.request {
-                    let _ =  message_client(ServerMessage::Text(format!(
-                            "You are currently banned\nRemaining time: {remaining_secs} seconds\n"
-                        )) , stream.as_ref());
-                        let _ = (*stream).as_ref().shutdown(net::Shutdown::Both);
-                    }
-                }
-                // Client is still banned
-                true
-            } else {
-                // Client no longer banned
-                log::info!("Client {ip_addr} has been unbanned");
-                let _ = self.ban_list.remove(&ip_addr);
-                false
-            }
-        } else {
+        self.ban_list.prune();
+        let Some(ban) = self.ban_list.find(ip_addr) else {
             // Client was not banned
-            false
+            return false;
+        };
+        log::debug!(
+            "IP {ip_addr} is currently banned by {range}. Remaining time: {remaining:?} seconds",
+            range = ban.range,
+            remaining = ban.remaining_secs()
+        );
+        let message = ServerMessage::Banned {
+            reason: ban.reason.clone(),
+            remaining_secs: ban.remaining_secs(),
+        };
+        // Disconnect banned client if currently connected
+        if let Some(client) = self.remove_client(addr) {
+            let _ = message_client(message, &client.outbound);
+        } else {
+            // Refuse Connect Request
+            if let Request::Connect { outbound, .. } = &request.request {
+                let _ = message_client(message, outbound);
+                outbound.close();
+            }
         }
+        // Client is still banned
+        true
     }
 
     /// Connect client to server
-    fn connect_client(&mut self, addr: SocketAddr, stream: Arc<TcpStream>) -> Result<()>{
-        let id = self.clients.len() + 1;
-
-        if let Some(prev_client) = self.clients.insert(addr, Client{ id, stream }){
-            self.clients.insert(addr, prev_client);
+    fn connect_client(
+        &mut self,
+        addr: SocketAddr,
+        outbound: Arc<Outbound>,
+        nickname: Option<String>,
+        capabilities: Vec<Capability>,
+        role: Role,
+        tls: bool,
+    ) -> Result<()> {
+        if self.clients.contains_key(&addr) {
             bail!("Client {addr} already connected");
         }
 
+        let mut session = Session::new(self.ids.allocate(), addr, capabilities, tls, outbound);
+        session.role = role;
+        log::info!("Client {addr} connected as {session} with role {role}");
+        if let Some(nickname) = nickname {
+            match self.check_nickname(addr, &nickname) {
+                Ok(()) => session.nickname = Some(nickname),
+                Err(e) => message_client(
+                    ServerMessage::Error(format!("Nickname {nickname} rejected: {e}")),
+                    &session.outbound,
+                )?,
+            }
+        }
+        message_client(
+            ServerMessage::Connected {
+                id: session.id,
+                name: session.display_name(),
+            },
+            &session.outbound,
+        )?;
+        if role != Role::User {
+            message_client(ServerMessage::RoleChanged(role), &session.outbound)?;
+        }
+        // Mutes outlive the session they were given to
+        if let Some(mute) = self.mutes.find(addr.ip(), None) {
+            message_client(mute.to_message(), &session.outbound)?;
+        }
+        message_client(
+            ServerMessage::JoinedRoom(DEFAULT_ROOM.to_owned()),
+            &session.outbound,
+        )?;
+        if let Some(motd) = &self.config.borrow().motd {
+            message_client(ServerMessage::Motd(motd.clone()), &session.outbound)?;
+        }
+        self.clients.insert(addr, session);
+        self.enter_room(addr, DEFAULT_ROOM)
+    }
 
-        Ok(())
-
+    /// Remove client session, leaving its room
+    fn remove_client(&mut self, addr: SocketAddr) -> Option<Session> {
+        let session = self.clients.remove(&addr)?;
+        self.rooms.leave(&session.room, addr);
+        let message = MessageToClient::new(MessageAuthor::Server(ServerMessage::PeerLeft {
+            id: session.id,
+            name: session.display_name(),
+        }));
+        let recipients = self.room_members(&session.room, None);
+        if let Err(e) = self.send_to_clients(&message, &recipients) {
+            log::error!("Unable to notify room {room}: {e}", room = session.room);
+        }
+        Some(session)
     }
 
     /// Disconnect client from server
     fn disconnect_client(&mut self, addr: SocketAddr) -> Result<()> {
         log::info!("Disconneting Client {addr}");
-        match self.clients.remove(&addr) {
+        match self.remove_client(addr) {
             None => bail!("Attempting to disconnect already disconnected Client {addr}"),
-            Some(client) => {
-                client
-                    .stream
-                    .as_ref()
-                    .shutdown(net::Shutdown::Both)
-                    .context("Unable to shutdown stream while disconnecting Client {addr}")?;
+            Some(session) => {
+                log::info!(
+                    "{session} ended after {secs} seconds",
+                    secs = Utc::now()
+                        .signed_duration_since(session.connected_at)
+                        .num_seconds()
+                );
                 Ok(())
             }
         }
     }
 
+    /// Check nickname is valid, not used by another client and not another user's account
+    fn check_nickname(&self, addr: SocketAddr, nickname: &str) -> Result<()> {
+        validate_nickname(nickname)?;
+        let own_account = self
+            .clients
+            .get(&addr)
+            .and_then(|session| session.account.as_ref())
+            .is_some_and(|account| account.to_lowercase() == nickname.to_lowercase());
+        if !own_account && self.accounts.is_registered(nickname) {
+            bail!("Nickname {nickname} belongs to a registered user")
+        }
+        let taken = self.clients.values().any(|session| {
+            session.addr != addr
+                && session
+                    .nickname
+                    .as_ref()
+                    .is_some_and(|other| other.to_lowercase() == nickname.to_lowercase())
+        });
+        if taken {
+            bail!("Nickname {nickname} is already taken")
+        }
+        Ok(())
+    }
+
+    /// Change client nickname and let everyone know
+    pub(crate) fn change_nickname(&mut self, addr: SocketAddr, nickname: String) -> Result<()> {
+        self.check_nickname(addr, &nickname)?;
+        let session = self
+            .clients
+            .get_mut(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        let old = session.display_name();
+        session.nickname = Some(nickname);
+        let message = MessageToClient::new(MessageAuthor::Server(ServerMessage::Renamed {
+            id: session.id,
+            old,
+            new: session.display_name(),
+        }));
+        log::info!("{session} renamed: {message:?}");
+        let recipients: Vec<SocketAddr> = self.clients.keys().copied().collect();
+        self.send_to_clients(&message, &recipients)
+    }
 
-    fn broadcast(&self, author_addr: SocketAddr, text: &str) -> Result<()> {
+    /// Clients in room, except `exclude`
+    fn room_members(&self, room: &str, exclude: Option<SocketAddr>) -> Vec<SocketAddr> {
+        self.rooms
+            .members(room)
+            .filter(|addr| Some(*addr) != exclude)
+            .collect()
+    }
+
+    /// Put client in room and let its members know
+    fn enter_room(&mut self, addr: SocketAddr, room: &str) -> Result<()> {
+        let session = self
+            .clients
+            .get_mut(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        self.rooms.join(room, addr)?;
+        session.room = room.to_owned();
+        log::info!("{session} entered room {room}");
+        let message = MessageToClient::new(MessageAuthor::Server(ServerMessage::PeerJoined {
+            id: session.id,
+            name: session.display_name(),
+        }));
+        let recipients = self.room_members(room, Some(addr));
+        self.send_to_clients(&message, &recipients)
+    }
+
+    /// Take client out of its current room and let the remaining members know
+    fn exit_room(&mut self, addr: SocketAddr) -> Result<()> {
+        let session = self
+            .clients
+            .get(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        let room = session.room.clone();
+        self.rooms.leave(&room, addr);
+        log::info!("{session} left room {room}");
+        let message = MessageToClient::new(MessageAuthor::Server(ServerMessage::PeerLeft {
+            id: session.id,
+            name: session.display_name(),
+        }));
+        let recipients = self.room_members(&room, None);
+        self.send_to_clients(&message, &recipients)
+    }
+
+    /// Move client to another existing room
+    pub(crate) fn join_room(&mut self, addr: SocketAddr, room: &str) -> Result<()> {
+        let session = self
+            .clients
+            .get(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        if session.room == room {
+            bail!("Already in room {room}")
+        }
+        if !self.rooms.exists(room) {
+            bail!("No such room {room}")
+        }
+        let outbound = session.outbound.clone();
+        self.exit_room(addr)?;
+        self.enter_room(addr, room)?;
+        message_client(ServerMessage::JoinedRoom(room.to_owned()), &outbound)
+    }
+
+    /// Create new room and move client into it
+    pub(crate) fn create_room(&mut self, addr: SocketAddr, room: &str) -> Result<()> {
+        self.rooms.create(room)?;
+        log::info!("Client {addr} created room {room}");
+        self.join_room(addr, room)
+    }
+
+    /// Send list of rooms to client
+    pub(crate) fn list_rooms(&self, addr: SocketAddr) -> Result<()> {
+        let session = self
+            .clients
+            .get(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        message_client(
+            ServerMessage::RoomList(self.rooms.list()),
+            &session.outbound,
+        )
+    }
+
+    /// Send list of users in the client's room
+    pub(crate) fn list_users(&self, addr: SocketAddr) -> Result<()> {
+        let session = self
+            .clients
+            .get(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        let mut users: Vec<UserInfo> = self
+            .rooms
+            .members(&session.room)
+            .filter_map(|member| self.clients.get(&member))
+            .map(|member| UserInfo {
+                id: member.id,
+                name: member.display_name(),
+            })
+            .collect();
+        users.sort_by_key(|user| user.id);
+        message_client(
+            ServerMessage::UserList {
+                room: session.room.clone(),
+                users,
+            },
+            &session.outbound,
+        )
+    }
+
+    /// Send usage of one command, or of every command available to the client
+    pub(crate) fn help(&self, addr: SocketAddr, name: Option<&str>) -> Result<()> {
+        let session = self
+            .clients
+            .get(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        let commands = match name {
+            Some(name) => {
+                let name = name.strip_prefix('/').unwrap_or(name);
+                match self.commands.get(name) {
+                    Some(command) if session.role >= command.permission => vec![command.info()],
+                    _ => bail!("No such command /{name}"),
+                }
+            }
+            None => self
+                .commands
+                .available(session.role)
+                .map(|command| command.info())
+                .collect(),
+        };
+        message_client(ServerMessage::Help(commands), &session.outbound)
+    }
+
+    /// Check permissions and arguments, then run command handler
+    fn run_command(
+        &mut self,
+        addr: SocketAddr,
+        name: &str,
+        args: &str,
+    ) -> Result<(), CommandError> {
+        let session = self
+            .clients
+            .get(&addr)
+            .ok_or(CommandError::Failed("Session not found".to_owned()))?;
+        let command = *self.commands.get(name).ok_or(CommandError::Unknown)?;
+        if session.role < command.permission {
+            log::warn!(
+                "{session} ({role}) is not allowed to run /{name}",
+                role = session.role
+            );
+            return Err(CommandError::PermissionDenied);
+        }
+        if let Some(capability) = command.capability {
+            if !session.capabilities.contains(&capability) {
+                return Err(CommandError::MissingCapability(capability));
+            }
+        }
+        let args = command
+            .parse_args(args)
+            .ok_or_else(|| CommandError::Usage(command.usage()))?;
+        log::info!("{session} runs /{name}");
+        (command.handler)(self, addr, &args).map_err(|e| CommandError::Failed(format!("{e:#}")))
+    }
+
+    /// Queue message to recipients, dropping clients that fell too far behind
+    fn send_to_clients(
+        &mut self,
+        message: &MessageToClient,
+        recipients: &[SocketAddr],
+    ) -> Result<()> {
+        // Encode frame once for all peers
+        let frame = outbound::encode_frame(message)?;
+        // Peers which fell too far behind
+        let mut overflowed_peers = Vec::new();
+        for (peer_addr, peer_client) in recipients
+            .iter()
+            .filter_map(|addr| self.clients.get(addr).map(|client| (addr, client)))
+        {
+            log::debug!("Sending message to Client {peer_addr}");
+            match peer_client.outbound.push(frame.clone()) {
+                Enqueued::Queued => {}
+                Enqueued::DroppedOldest => {
+                    log::debug!("Client {peer_addr} is lagging, dropped oldest message")
+                }
+                Enqueued::Lagging => {
+                    log::debug!("Client {peer_addr} is lagging, dropped new message")
+                }
+                Enqueued::Disconnected | Enqueued::Closed => overflowed_peers.push(*peer_addr),
+            }
+        }
+        for peer_addr in overflowed_peers {
+            log::warn!("Client {peer_addr} fell too far behind, disconnecting");
+            self.remove_client(peer_addr);
+        }
+        Ok(())
+    }
+
+    fn broadcast(&mut self, author_addr: SocketAddr, text: &str) -> Result<()> {
         log::trace!("Broadcasting message from client {author_addr}");
-        let id = self
+        let author = self
             .clients
             .get(&author_addr)
-            .ok_or(anyhow!("Client {author_addr} id not found"))?
-            .id;
-        let message =  MessageToClient::new(MessageAuthor::Peer { id, content: PeerMessage::Text(text.to_owned()) });
+            .ok_or(anyhow!("Client {author_addr} session not found"))?;
+        // Muted clients are only reminded of it
+        if let Some(remaining_secs) = self.muted_secs(author) {
+            log::debug!("{author} is muted, dropping message");
+            return message_client(
+                ServerMessage::Muted {
+                    remaining_secs,
+                    reason: None,
+                },
+                &author.outbound,
+            );
+        }
+        let message = MessageToClient::new(MessageAuthor::Peer {
+            id: author.id,
+            name: author.display_name(),
+            content: PeerMessage::Text(text.to_owned()),
+        });
         log::debug!("Message: {message:?}");
-        self.clients.iter().filter(|(peer_addr, _)| **peer_addr != author_addr ).for_each(|(peer_addr, peer_client)| 
-            {
-                log::debug!("Sending message from Client {author_addr} to Client {peer_addr}");
-                if let Err(e) = message.write_to(peer_client.stream.as_ref()) {
-                    log::error!(
-                        "Unable to broadcast message from Client {author_addr} to Client {peer_addr}: {e}"
-                    );
-                }
+        let recipients = self.room_members(&author.room, Some(author_addr));
+        self.send_to_clients(&message, &recipients)
+    }
 
-            });
-        Ok(())
+    /// Find session addressed by recipient
+    fn find_recipient(&self, recipient: &Recipient) -> Option<&Session> {
+        self.clients.values().find(|session| match recipient {
+            Recipient::Id(id) => session.id == *id,
+            Recipient::Nickname(nickname) => session
+                .nickname
+                .as_ref()
+                .is_some_and(|other| other.to_lowercase() == nickname.to_lowercase()),
+        })
+    }
+
+    /// Send text to a single peer
+    pub(crate) fn direct_message(
+        &mut self,
+        author_addr: SocketAddr,
+        recipient: &Recipient,
+        text: &str,
+    ) -> Result<()> {
+        let author = self
+            .clients
+            .get(&author_addr)
+            .ok_or(anyhow!("Client {author_addr} session not found"))?;
+        let target = self
+            .find_recipient(recipient)
+            .ok_or(anyhow!("User {recipient} is not online"))?;
+        if target.addr == author_addr {
+            bail!("You cannot send a direct message to yourself")
+        }
+        if let Some(remaining_secs) = self.muted_secs(author) {
+            bail!("You are muted for {remaining_secs} more seconds")
+        }
+        log::debug!("Direct message from {author} to {target}");
+        let message = MessageToClient::new(MessageAuthor::Peer {
+            id: author.id,
+            name: author.display_name(),
+            content: PeerMessage::Direct(text.to_owned()),
+        });
+        let recipients = [target.addr];
+        self.send_to_clients(&message, &recipients)
+    }
+
+    /// Store key published by client, share it with peers supporting encryption
+    /// and send the client the keys of those peers
+    fn publish_key(&mut self, addr: SocketAddr, key: PublicKey) -> Result<()> {
+        let session = self
+            .clients
+            .get_mut(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        if !session.capabilities.contains(&Capability::Encryption) {
+            bail!("Requires the {} capability", Capability::Encryption)
+        }
+        session.public_key = Some(key);
+        log::info!(
+            "{session} published encryption key {fingerprint}",
+            fingerprint = key.fingerprint()
+        );
+        let announcement = MessageToClient::new(MessageAuthor::Server(ServerMessage::PeerKey {
+            id: session.id,
+            nickname: session.nickname.clone(),
+            key,
+        }));
+
+        let session = &self.clients[&addr];
+        let peers: Vec<&Session> = self
+            .clients
+            .values()
+            .filter(|peer| peer.addr != addr && peer.capabilities.contains(&Capability::Encryption))
+            .collect();
+        for peer in &peers {
+            if let Some(key) = peer.public_key {
+                message_client(
+                    ServerMessage::PeerKey {
+                        id: peer.id,
+                        nickname: peer.nickname.clone(),
+                        key,
+                    },
+                    &session.outbound,
+                )?;
+            }
+        }
+        let recipients: Vec<SocketAddr> = peers.iter().map(|peer| peer.addr).collect();
+        self.send_to_clients(&announcement, &recipients)
+    }
+
+    /// Route end-to-end encrypted direct message to a single peer without reading it
+    fn encrypted_message(
+        &mut self,
+        author_addr: SocketAddr,
+        recipient: &Recipient,
+        payload: EncryptedPayload,
+    ) -> Result<()> {
+        let author = self
+            .clients
+            .get(&author_addr)
+            .ok_or(anyhow!("Client {author_addr} session not found"))?;
+        if author.public_key.is_none() {
+            bail!("Publish your encryption key before sending encrypted messages")
+        }
+        let target = self
+            .find_recipient(recipient)
+            .ok_or(anyhow!("User {recipient} is not online"))?;
+        if target.addr == author_addr {
+            bail!("You cannot send a direct message to yourself")
+        }
+        if target.public_key.is_none() {
+            bail!(
+                "User {name} has no encryption key",
+                name = target.display_name()
+            )
+        }
+        if let Some(remaining_secs) = self.muted_secs(author) {
+            bail!("You are muted for {remaining_secs} more seconds")
+        }
+        log::debug!("Encrypted message from {author} to {target}");
+        let message = MessageToClient::new(MessageAuthor::Peer {
+            id: author.id,
+            name: author.display_name(),
+            content: PeerMessage::Encrypted(payload),
+        });
+        let recipients = [target.addr];
+        self.send_to_clients(&message, &recipients)
     }
 
     // Shutdown client, optionally sending a final message
-    fn shutdown_client(&mut self, addr: SocketAddr, text: Option<&str>) {
+    fn shutdown_client(&mut self, addr: SocketAddr, message: Option<ServerMessage>) {
         log::info!("Shutting down Client {addr}");
-        if let Some(client) = self.clients.remove(&addr) {
-            if let Some(text) = text {
-                let _ = message_client(ServerMessage::Text(text.to_owned()), client.stream.as_ref());
+        if let Some(client) = self.remove_client(addr) {
+            if let Some(message) = message {
+                let _ = message_client(message, &client.outbound);
             }
-            let _ = client.stream.as_ref().shutdown(net::Shutdown::Both);
         }
     }
 
-    // Ban a given client
-    fn ban_client(&mut self, addr: SocketAddr, reason: BanReason) {
-        let ip = addr.ip();
+    /// Ban range of addresses, disconnecting every client in it.
+    /// Bans without duration are permanent.
+    fn apply_ban(
+        &mut self,
+        range: IpNet,
+        account: Option<String>,
+        reason: BanReason,
+        duration: Option<TimeDelta>,
+        issued_by: &str,
+    ) -> Result<()> {
+        let now = Utc::now();
+        let expires_at = match duration {
+            Some(duration) => Some(
+                now.checked_add_signed(duration)
+                    .context("Ban duration too long")?,
+            ),
+            None => None,
+        };
         log::info!(
-            "Banning IP {ip}. Reason: {reason}. Ban time: {ban_time} seconds",
-            ban_time = TOTAL_BAN_TIME.num_seconds()
+            "Banning {range}{account}. Reason: {reason}. Ban time: {ban_time}. Issued by: {issued_by}",
+            account = account
+                .as_ref()
+                .map(|account| format!(" and account {account}"))
+                .unwrap_or_default(),
+            ban_time = match duration {
+                Some(duration) => format!("{secs} seconds", secs = duration.num_seconds()),
+                None => "permanent".to_owned(),
+            }
         );
-        self.ban_list.insert(ip, Utc::now());
-        // Disconnect client
+        self.ban_list.insert(Ban {
+            range,
+            account: account.clone(),
+            expires_at,
+            reason: reason.clone(),
+            issued_by: issued_by.to_owned(),
+            issued_at: now,
+        });
+        // Disconnect clients, wherever the account is logged in from
+        let banned: Vec<SocketAddr> = self
+            .clients
+            .values()
+            .filter(|session| {
+                range.contains(&session.addr.ip())
+                    || session.account.as_ref().is_some_and(|logged_in| {
+                        account.as_ref().is_some_and(|account| {
+                            logged_in.to_lowercase() == account.to_lowercase()
+                        })
+                    })
+            })
+            .map(|session| session.addr)
+            .collect();
+        for addr in banned {
+            self.shutdown_client(
+                addr,
+                Some(ServerMessage::Banned {
+                    reason: reason.clone(),
+                    remaining_secs: duration.map(|duration| duration.num_seconds()),
+                }),
+            );
+        }
+        Ok(())
+    }
+
+    // Ban a given client for longer each time it offends again
+    fn ban_client(&mut self, addr: SocketAddr, reason: BanReason) -> Result<()> {
+        let duration = self.ban_list.record_offense(addr.ip());
+        let account = self
+            .clients
+            .get(&addr)
+            .and_then(|session| session.account.clone());
+        self.apply_ban(
+            IpNet::from(addr.ip()),
+            account,
+            reason,
+            Some(duration),
+            "server",
+        )
+    }
+
+    /// Find session a moderator may act upon
+    fn moderation_target(&self, addr: SocketAddr, target: &Recipient) -> Result<&Session> {
+        let moderator = self
+            .clients
+            .get(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        let session = self
+            .find_recipient(target)
+            .ok_or(anyhow!("User {target} is not online"))?;
+        if session.addr == addr {
+            bail!("You cannot moderate yourself")
+        }
+        if session.role >= moderator.role {
+            bail!(
+                "{name} has role {role} and cannot be moderated by you",
+                name = session.display_name(),
+                role = session.role
+            )
+        }
+        Ok(session)
+    }
+
+    /// Let moderator know the outcome of a command
+    fn confirm(&self, addr: SocketAddr, text: String) -> Result<()> {
+        let session = self
+            .clients
+            .get(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        message_client(ServerMessage::Text(text), &session.outbound)
+    }
+
+    /// Disconnect user
+    pub(crate) fn kick(
+        &mut self,
+        addr: SocketAddr,
+        target: &Recipient,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let session = self.moderation_target(addr, target)?;
+        let (target_addr, name) = (session.addr, session.display_name());
+        log::info!("Client {addr} kicked {session}. Reason: {reason:?}");
         self.shutdown_client(
+            target_addr,
+            Some(ServerMessage::Kicked {
+                reason: reason.map(str::to_owned),
+            }),
+        );
+        self.confirm(addr, format!("Kicked {name}"))
+    }
+
+    /// Ban user IP address, and account if logged in. Bans without duration are permanent.
+    pub(crate) fn ban(
+        &mut self,
+        addr: SocketAddr,
+        target: &Recipient,
+        duration: Option<TimeDelta>,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let target = self.moderation_target(addr, target)?;
+        let (ip, account) = (target.addr.ip(), target.account.clone());
+        self.ban_range_of(addr, IpNet::from(ip), account, duration, reason)?;
+        // Later automatic bans take this one into account
+        self.ban_list.record_offense(ip);
+        Ok(())
+    }
+
+    /// Ban range of IP addresses. Bans without duration are permanent.
+    pub(crate) fn ban_range(
+        &mut self,
+        addr: SocketAddr,
+        range: IpNet,
+        duration: Option<TimeDelta>,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        self.ban_range_of(addr, range, None, duration, reason)
+    }
+
+    /// Ban range of IP addresses along with an account on behalf of moderator
+    fn ban_range_of(
+        &mut self,
+        addr: SocketAddr,
+        range: IpNet,
+        account: Option<String>,
+        duration: Option<TimeDelta>,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let moderator = self
+            .clients
+            .get(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        // Moderators may only ban clients below them, themselves included
+        if let Some(session) = self
+            .clients
+            .values()
+            .find(|session| range.contains(&session.addr.ip()) && session.role >= moderator.role)
+        {
+            bail!(
+                "{range} includes {name}, who cannot be banned by you",
+                name = session.display_name()
+            )
+        }
+        let issued_by = moderator.display_name();
+        let reason = BanReason::Other(reason.unwrap_or("No reason given").to_owned());
+        let banned = match &account {
+            Some(account) => format!("{range} and account {account}"),
+            None => range.to_string(),
+        };
+        self.apply_ban(range, account, reason, duration, &issued_by)?;
+        self.confirm(
             addr,
-            Some(&format!(
-                "You have been banned. Reason: {reason}. Ban time: {ban_time} seconds\n",
-                ban_time = TOTAL_BAN_TIME.num_seconds()
-            )),
+            match duration {
+                Some(duration) => format!(
+                    "Banned {banned} for {secs} seconds",
+                    secs = duration.num_seconds()
+                ),
+                None => format!("Banned {banned} permanently"),
+            },
+        )
+    }
+
+    /// Lift bans of exactly this range of IP addresses
+    pub(crate) fn unban(&mut self, addr: SocketAddr, range: IpNet) -> Result<()> {
+        let bans = self.ban_list.remove(&range);
+        if bans.is_empty() {
+            bail!("{range} is not banned")
+        }
+        for ban in &bans {
+            log::info!(
+                "Client {addr} unbanned {range}{account}, banned by {issued_by}",
+                account = ban
+                    .account
+                    .as_ref()
+                    .map(|account| format!(" and account {account}"))
+                    .unwrap_or_default(),
+                issued_by = ban.issued_by
+            );
+        }
+        self.confirm(addr, format!("Unbanned {range}"))
+    }
+
+    /// Send bans in effect to moderator
+    pub(crate) fn list_bans(&self, addr: SocketAddr) -> Result<()> {
+        let session = self
+            .clients
+            .get(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        let bans = self.ban_list.iter().cloned().collect();
+        message_client(ServerMessage::BanList(bans), &session.outbound)
+    }
+
+    /// Prevent user from sending messages for duration
+    pub(crate) fn mute(
+        &mut self,
+        addr: SocketAddr,
+        target: &Recipient,
+        duration: TimeDelta,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let target = self.moderation_target(addr, target)?;
+        let (target_addr, name) = (target.addr, target.display_name());
+        self.mute_client(target_addr, duration, reason)?;
+        log::info!(
+            "Client {addr} muted {name} for {secs} seconds. Reason: {reason:?}",
+            secs = duration.num_seconds()
         );
+        self.confirm(
+            addr,
+            format!(
+                "Muted {name} for {secs} seconds",
+                secs = duration.num_seconds()
+            ),
+        )
     }
 
+    /// Seconds left until client may send messages again, if muted
+    fn muted_secs(&self, session: &Session) -> Option<i64> {
+        self.mutes
+            .find(session.addr.ip(), session.account.as_deref())?
+            .remaining_secs()
+    }
 
-    /// Run server
-    pub fn run(mut self) -> Result<()> {
-        log::trace!("Launching chat server");
+    /// Prevent client address and account from sending messages for a duration and notify it
+    fn mute_client(
+        &mut self,
+        addr: SocketAddr,
+        duration: TimeDelta,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let session = self
+            .clients
+            .get(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        let until = Utc::now()
+            .checked_add_signed(duration)
+            .context("Mute duration too long")?;
+        let mute = Mute {
+            until,
+            reason: reason.map(str::to_owned),
+        };
+        message_client(mute.to_message(), &session.outbound)?;
+        self.mutes
+            .insert(addr.ip(), session.account.as_deref(), mute);
+        Ok(())
+    }
 
-        // Main server loop
-        loop {
-            // Try to receive a request from a client thread
-            let request = match self.receiver.recv() {
-                Err(e) => {
-                    log::error!("Server could not receive message: {e}");
-                    continue;
+    /// Allow muted user to send messages again
+    pub(crate) fn unmute(&mut self, addr: SocketAddr, target: &Recipient) -> Result<()> {
+        let target_addr = self.moderation_target(addr, target)?.addr;
+        let session = self
+            .clients
+            .get(&target_addr)
+            .ok_or(anyhow!("Client {target_addr} session not found"))?;
+        if !self
+            .mutes
+            .remove(target_addr.ip(), session.account.as_deref())
+        {
+            bail!("{name} is not muted", name = session.display_name())
+        }
+        log::info!("Client {addr} unmuted {session}");
+        let name = session.display_name();
+        message_client(ServerMessage::Unmuted, &session.outbound)?;
+        self.confirm(addr, format!("Unmuted {name}"))
+    }
+
+    /// Change role of user
+    pub(crate) fn set_role(
+        &mut self,
+        addr: SocketAddr,
+        target: &Recipient,
+        role: Role,
+    ) -> Result<()> {
+        let target_addr = self.moderation_target(addr, target)?.addr;
+        let session = self
+            .clients
+            .get_mut(&target_addr)
+            .ok_or(anyhow!("Client {target_addr} session not found"))?;
+        // Roles of logged in users stick to their account
+        if let Some(account) = &session.account {
+            self.accounts.set_role(account, role)?;
+        }
+        session.role = role;
+        log::info!("Client {addr} made {session} a {role}");
+        let name = session.display_name();
+        message_client(ServerMessage::RoleChanged(role), &session.outbound)?;
+        self.confirm(addr, format!("{name} is now a {role}"))
+    }
+
+    /// Reload configuration, keeping the current one if the new one is invalid
+    fn reload_config(&mut self) -> Result<()> {
+        let config = self.config_source.load()?;
+        let current = self.config.borrow().clone();
+
+        // Prepare everything that may fail before applying anything
+        let ban_list = if config.bans.file != current.bans.file {
+            Some(
+                BanList::load(&config.bans.file, config.bans.escalation)
+                    .context("Unable to load ban list")?,
+            )
+        } else {
+            None
+        };
+        // Generated tokens are kept unless authentication settings change
+        let access_tokens = if config.auth != current.auth || !config.auth.tokens.is_empty() {
+            Some(self.access_tokens.borrow().reload(&config.auth)?)
+        } else {
+            None
+        };
+
+        // Bans
+        if let Some(ban_list) = ban_list {
+            self.ban_list = ban_list;
+        }
+        self.ban_list.set_escalation(config.bans.escalation);
+
+        // Authentication of new connections
+        if let Some(access_tokens) = access_tokens {
+            self.access_tokens.send_replace(Arc::new(access_tokens));
+        }
+
+        // Message of the day
+        if config.motd != current.motd {
+            if let Some(motd) = &config.motd {
+                let message =
+                    MessageToClient::new(MessageAuthor::Server(ServerMessage::Motd(motd.clone())));
+                let recipients: Vec<_> = self.clients.keys().copied().collect();
+                self.send_to_clients(&message, &recipients)?;
+            }
+        }
+
+        if config.listeners != current.listeners
+            || config.tls != current.tls
+            || config.limits.max_unauthenticated != current.limits.max_unauthenticated
+            || config.accounts.file != current.accounts.file
+            || config.logging.level != current.logging.level
+            || config.logging.colors != current.logging.colors
+        {
+            log::warn!(
+                "Changes to listeners, TLS, the accounts file or logging take effect after a restart"
+            );
+        }
+
+        // Limits are picked up by client tasks
+        self.config.send_replace(Arc::new(config));
+        log::info!("Configuration reloaded");
+        Ok(())
+    }
+
+    /// Reload configuration on behalf of admin
+    pub(crate) fn reload(&mut self, addr: SocketAddr) -> Result<()> {
+        log::info!("Client {addr} reloads configuration");
+        self.reload_config()?;
+        self.confirm(addr, "Configuration reloaded".to_owned())
+    }
+
+    /// Handle request from client task
+    fn handle_request(&mut self, request: ClientRequest) {
+        log::debug!("Server received message: {request}");
+
+        // Ban filter
+        if self.ban_filter(&request) {
+            return;
+        }
+
+        // Address of the client that made the request
+        let addr = request.addr;
+
+        // Handle client request
+        match request.request {
+            Request::Connect {
+                outbound,
+                nickname,
+                capabilities,
+                role,
+                tls,
+            } => {
+                if let Err(e) =
+                    self.connect_client(addr, outbound.clone(), nickname, capabilities, role, tls)
+                {
+                    log::error!("Unable to connect Client {addr}: {e}");
+                    outbound.close();
                 }
-                Ok(request) => request,
-            };
-            log::debug!("Server received message: {request}");
+            }
 
-            // Ban filter
-            if self.ban_filter(&request) {
-                continue;
+            Request::Disconnet => {
+                if let Err(e) = self.disconnect_client(addr) {
+                    log::error!("Unable to disconnect Client {addr}: {e}");
+                }
             }
 
-            // Address of the client that made the request
-            let addr = request.addr;
+            Request::Ban(reason) => {
+                if let Err(e) = self.ban_client(addr, reason) {
+                    log::error!("Unable to ban client {addr}: {e}");
+                }
+            }
 
-            // Handle client request
-            match request.request {
-                Request::Connect(stream) => {
-                    if let Err(e) = self.connect_client(addr, stream.clone()) {
-                        log::error!("Unable to connect Client {addr}: {e}");
-                        let _ = stream.shutdown(net::Shutdown::Both);
-                    }
+            Request::Mute(duration) => {
+                log::info!(
+                    "Client {addr} muted for {secs} seconds for exceeding rate limits",
+                    secs = duration.as_secs()
+                );
+                let duration = TimeDelta::from_std(duration).unwrap_or(TimeDelta::MAX);
+                if let Err(e) = self.mute_client(addr, duration, Some("Rate limit exceeded")) {
+                    log::error!("Unable to mute client {addr}: {e}");
+                }
+            }
+
+            Request::Lockout(duration) => {
+                let duration = TimeDelta::from_std(duration).unwrap_or(TimeDelta::MAX);
+                if let Err(e) = self.apply_ban(
+                    IpNet::from(addr.ip()),
+                    None,
+                    BanReason::FailedLogins,
+                    Some(duration),
+                    "server",
+                ) {
+                    log::error!("Unable to lock out client {addr}: {e}");
                 }
+            }
 
-                Request::Disconnet => {
-                    if let Err(e) = self.disconnect_client(addr) {
-                        log::error!("Unable to disconnect Client {addr}: {e}");
-                    }
+            Request::Broadcast(text) => {
+                log::info!("Client {addr} says: {text}");
+                if let Err(e) = self.broadcast(addr, &text) {
+                    log::error!("Unable to broadcast message: {e}");
                 }
+            }
 
-                Request::Ban(reason) => {
-                    self.ban_client(addr, reason);
+            Request::Login { username, role } => {
+                if let Err(e) = self.login_client(addr, username, role) {
+                    log::error!("Unable to log Client {addr} in: {e}");
                 }
+            }
+
+            Request::PublishKey(key) => {
+                if let Err(e) = self.publish_key(addr, key) {
+                    log::debug!("Client {addr} could not publish key: {e}");
+                    self.report_error(addr, e);
+                }
+            }
 
-                Request::Broadcast(text) => {
-                    log::info!("Client {addr} says: {text}");
-                    if let Err(e) = self.broadcast(addr, &text) {
-                        log::error!("Unable to broadcast message: {e}");
+            Request::Encrypted { recipient, payload } => {
+                if let Err(e) = self.encrypted_message(addr, &recipient, payload) {
+                    log::debug!("Client {addr} could not send encrypted message: {e}");
+                    self.report_error(addr, e);
+                }
+            }
+
+            Request::Command { name, args } => {
+                if let Err(error) = self.run_command(addr, &name, &args) {
+                    log::debug!("Client {addr} command /{name} failed: {error}");
+                    if let Some(session) = self.clients.get(&addr) {
+                        let _ = message_client(
+                            ServerMessage::CommandError {
+                                command: name,
+                                error,
+                            },
+                            &session.outbound,
+                        );
                     }
                 }
             }
         }
     }
+
+    /// Let client know its request failed
+    fn report_error(&self, addr: SocketAddr, error: anyhow::Error) {
+        if let Some(session) = self.clients.get(&addr) {
+            let _ = message_client(
+                ServerMessage::Error(format!("{error:#}")),
+                &session.outbound,
+            );
+        }
+    }
+
+    /// Bind account to client session, applying its bans, role and nickname
+    fn login_client(&mut self, addr: SocketAddr, username: String, role: Role) -> Result<()> {
+        if let Some(ban) = self.ban_list.find_account(&username) {
+            log::info!("Account {username} is banned, disconnecting Client {addr}");
+            let message = ServerMessage::Banned {
+                reason: ban.reason.clone(),
+                remaining_secs: ban.remaining_secs(),
+            };
+            self.shutdown_client(addr, Some(message));
+            return Ok(());
+        }
+
+        // Only the latest session stays logged into an account
+        let previous: Vec<SocketAddr> =
+            self.clients
+                .values()
+                .filter(|session| {
+                    session.addr != addr
+                        && session.account.as_ref().is_some_and(|account| {
+                            account.to_lowercase() == username.to_lowercase()
+                        })
+                })
+                .map(|session| session.addr)
+                .collect();
+        for previous_addr in previous {
+            log::info!("Account {username} logged in again, disconnecting Client {previous_addr}");
+            self.shutdown_client(
+                previous_addr,
+                Some(ServerMessage::Kicked {
+                    reason: Some("Logged in from another connection".to_owned()),
+                }),
+            );
+        }
+
+        let session = self
+            .clients
+            .get_mut(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        session.account = Some(username.clone());
+        // Keep the higher of the roles granted by the token and the account
+        let role = session.role.max(role);
+        let promoted = role != session.role;
+        session.role = role;
+        log::info!("{session} logged into account {username} with role {role}");
+        message_client(
+            ServerMessage::LoggedIn {
+                username: username.clone(),
+                role,
+            },
+            &session.outbound,
+        )?;
+        if promoted {
+            message_client(ServerMessage::RoleChanged(role), &session.outbound)?;
+        }
+        // Mutes follow the account to other addresses, and the address into the account
+        if let Some(mute) = self.mutes.find(addr.ip(), Some(&username)).cloned() {
+            message_client(mute.to_message(), &session.outbound)?;
+            self.mutes.insert(addr.ip(), Some(&username), mute);
+        }
+
+        // Take the username as nickname
+        if session.nickname.as_ref() != Some(&username) {
+            if let Err(e) = self.change_nickname(addr, username) {
+                self.confirm(addr, format!("Nickname not changed: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace access token by a new one, accepting the old one for a grace period
+    pub(crate) fn rotate_token(
+        &mut self,
+        addr: SocketAddr,
+        label: &str,
+        grace: Option<TimeDelta>,
+    ) -> Result<()> {
+        // The new token is sent back, so it must not cross the wire in clear
+        let session = self
+            .clients
+            .get(&addr)
+            .ok_or(anyhow!("Client {addr} session not found"))?;
+        if !session.tls {
+            bail!("Tokens can only be rotated over an encrypted connection")
+        }
+        let auth = self.config.borrow().auth.clone();
+        let grace = grace.unwrap_or(auth.rotation_grace);
+        let mut access_tokens = AccessTokens::clone(&self.access_tokens.borrow());
+        let rotated = access_tokens.rotate(label, auth.token_length, grace)?;
+        self.access_tokens.send_replace(Arc::new(access_tokens));
+        log::info!(
+            "Client {addr} rotated token {label}, old token valid for {secs} more seconds",
+            secs = grace.num_seconds()
+        );
+        let mut text = format!(
+            "Token {label} rotated, old token valid for {secs} more seconds. New token: {token}",
+            secs = grace.num_seconds(),
+            token = rotated.token
+        );
+        if !rotated.is_persisted() {
+            text.push_str(". Update the configuration to keep it after a restart.");
+        }
+        self.confirm(addr, text)
+    }
+
+    /// Run server
+    pub async fn run(mut self) -> Result<()> {
+        log::trace!("Launching chat server");
+
+        // Main server loop
+        loop {
+            tokio::select! {
+                request = self.receiver.recv() => match request {
+                    Some(request) => self.handle_request(request),
+                    None => break,
+                },
+                Some(()) = self.reload_receiver.recv() => {
+                    if let Err(e) = self.reload_config() {
+                        log::error!("Configuration not reloaded: {e:#}");
+                    }
+                }
+            }
+        }
+
+        log::info!("All request senders dropped, stopping server");
+        Ok(())
+    }
 }
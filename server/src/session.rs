@@ -0,0 +1,180 @@
+use std::{fmt::Display, net::SocketAddr, str::FromStr, sync::Arc};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{e2e::PublicKey, messages::Capability, outbound::Outbound, rooms::DEFAULT_ROOM};
+
+/// Unique identifier of a client session, never reused while the server runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SessionId(pub u64);
+
+impl Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{id}", id = self.0)
+    }
+}
+
+/// Privileges of a session, in increasing order
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum Role {
+    #[default]
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Role::User => "User",
+                Role::Moderator => "Moderator",
+                Role::Admin => "Admin",
+            }
+        )
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "user" => Ok(Role::User),
+            "moderator" | "mod" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => bail!("Unknown role {s}, expected user, moderator or admin"),
+        }
+    }
+}
+
+/// Minimum nickname length in characters
+pub const MIN_NICKNAME_LENGTH: usize = 2;
+
+/// Maximum nickname length in characters
+pub const MAX_NICKNAME_LENGTH: usize = 24;
+
+/// Check nickname length and characters
+pub fn validate_nickname(nickname: &str) -> Result<()> {
+    let len = nickname.chars().count();
+    if !(MIN_NICKNAME_LENGTH..=MAX_NICKNAME_LENGTH).contains(&len) {
+        bail!(
+            "Nickname must be between {MIN_NICKNAME_LENGTH} and {MAX_NICKNAME_LENGTH} characters long"
+        )
+    }
+    if !nickname.starts_with(|c: char| c.is_alphabetic()) {
+        bail!("Nickname must start with a letter")
+    }
+    if !nickname
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    {
+        bail!("Nickname may only contain letters, digits, `_` and `-`")
+    }
+    Ok(())
+}
+
+/// Allocates monotonically increasing session IDs
+#[derive(Debug)]
+pub struct IdAllocator {
+    next: u64,
+}
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        Self { next: 1 }
+    }
+}
+
+impl IdAllocator {
+    /// New allocator starting from ID 1
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocate next unused ID
+    pub fn allocate(&mut self) -> SessionId {
+        let id = SessionId(self.next);
+        self.next = self.next.checked_add(1).expect("Session IDs exhausted");
+        id
+    }
+}
+
+/// Client connected to the server
+#[derive(Debug)]
+pub struct Session {
+    /// Unique session ID
+    pub id: SessionId,
+    /// Remote address
+    pub addr: SocketAddr,
+    /// Time the client connected
+    pub connected_at: DateTime<Utc>,
+    /// Nickname chosen by the client
+    pub nickname: Option<String>,
+    /// Username of the account the client logged into
+    pub account: Option<String>,
+    /// Room the client is currently in
+    pub room: String,
+    /// Privileges of the client
+    pub role: Role,
+    /// Capabilities negotiated during handshake
+    pub capabilities: Vec<Capability>,
+    /// Whether the connection is encrypted with TLS
+    pub tls: bool,
+    /// Key peers encrypt direct messages to the client with, once published
+    pub public_key: Option<PublicKey>,
+    /// Queue of messages to be written to the client
+    pub(crate) outbound: Arc<Outbound>,
+}
+
+impl Session {
+    /// New session connected now
+    pub(crate) fn new(
+        id: SessionId,
+        addr: SocketAddr,
+        capabilities: Vec<Capability>,
+        tls: bool,
+        outbound: Arc<Outbound>,
+    ) -> Self {
+        Self {
+            id,
+            addr,
+            connected_at: Utc::now(),
+            nickname: None,
+            account: None,
+            room: DEFAULT_ROOM.to_owned(),
+            role: Role::default(),
+            capabilities,
+            tls,
+            public_key: None,
+            outbound,
+        }
+    }
+
+    /// Name shown to peers
+    pub fn display_name(&self) -> String {
+        match &self.nickname {
+            Some(nickname) => nickname.clone(),
+            None => format!("User {id}", id = self.id),
+        }
+    }
+}
+
+impl Display for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Session {id} ({addr})", id = self.id, addr = self.addr)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Stop client tasks once the session ends
+        self.outbound.close();
+    }
+}
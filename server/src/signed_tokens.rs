@@ -0,0 +1,214 @@
+use std::{fs, path::Path, str::FromStr};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey, SECRET_KEY_LENGTH};
+use getrandom::getrandom;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    session::{validate_nickname, Role},
+    utils::write_private,
+};
+
+/// Who a signed token was issued to and what it grants
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    /// User the token identifies, used as nickname
+    pub username: String,
+    /// Role granted by the token
+    pub role: Role,
+    pub issued_at: DateTime<Utc>,
+    /// Time the token stops working
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Ed25519 key signing tokens, held by administrators only.
+/// Tokens are the base64 CBOR encoded claims and their signature, separated by a dot.
+pub struct TokenSigner(SigningKey);
+
+impl TokenSigner {
+    /// Generate new random signing key
+    pub fn generate() -> Result<Self> {
+        let mut secret = [0; SECRET_KEY_LENGTH];
+        getrandom(&mut secret).map_err(|e| anyhow!("Unable to generate signing key: {e}"))?;
+        Ok(Self(SigningKey::from_bytes(&secret)))
+    }
+
+    /// Load signing key from file holding it in base64
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read signing key {}", path.display()))?;
+        let secret = STANDARD
+            .decode(content.trim())
+            .ok()
+            .and_then(|secret| <[u8; SECRET_KEY_LENGTH]>::try_from(secret).ok())
+            .with_context(|| format!("Invalid signing key {}", path.display()))?;
+        Ok(Self(SigningKey::from_bytes(&secret)))
+    }
+
+    /// Save signing key to file readable by its owner only
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let secret = STANDARD.encode(self.0.to_bytes());
+        write_private(path, &format!("{secret}\n"))
+            .with_context(|| format!("Unable to save signing key to {}", path.display()))
+    }
+
+    /// Public key verifying the tokens, in base64 as configured on the server
+    pub fn public_key(&self) -> String {
+        STANDARD.encode(self.0.verifying_key().to_bytes())
+    }
+
+    /// Sign token carrying claims
+    pub fn issue(&self, claims: &Claims) -> Result<String> {
+        validate_nickname(&claims.username).context("Invalid username")?;
+        let mut payload = Vec::new();
+        ciborium::into_writer(claims, &mut payload).context("Unable to encode token claims")?;
+        let signature = self.0.sign(&payload);
+        Ok(format!(
+            "{payload}.{signature}",
+            payload = URL_SAFE_NO_PAD.encode(&payload),
+            signature = URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        ))
+    }
+}
+
+/// Public key verifying signed tokens offline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenVerifier(VerifyingKey);
+
+impl FromStr for TokenVerifier {
+    type Err = anyhow::Error;
+
+    /// Parse public key from base64
+    fn from_str(s: &str) -> Result<Self> {
+        let key = STANDARD
+            .decode(s.trim())
+            .ok()
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .context("Expected 32 bytes in base64")?;
+        VerifyingKey::from_bytes(&key)
+            .map(Self)
+            .map_err(|e| anyhow!("Invalid public key: {e}"))
+    }
+}
+
+impl TokenVerifier {
+    /// Claims of token if it was signed by the key and has not expired yet
+    pub fn verify(&self, token: &str) -> Result<Claims> {
+        let (payload, signature) = token
+            .trim()
+            .split_once('.')
+            .context("Malformed signed token")?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .context("Malformed signed token")?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .ok()
+            .and_then(|signature| Signature::from_slice(&signature).ok())
+            .context("Malformed signed token")?;
+        self.0
+            .verify_strict(&payload, &signature)
+            .map_err(|_| anyhow!("Invalid token signature"))?;
+        let claims: Claims =
+            ciborium::from_reader(payload.as_slice()).context("Malformed token claims")?;
+        if claims.expires_at <= Utc::now() {
+            bail!(
+                "Token of {username} expired at {expires_at}",
+                username = claims.username,
+                expires_at = claims.expires_at
+            )
+        }
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    fn claims(role: Role, expires_in: TimeDelta) -> Claims {
+        Claims {
+            username: "alice".to_owned(),
+            role,
+            issued_at: Utc::now(),
+            expires_at: Utc::now() + expires_in,
+        }
+    }
+
+    fn verifier(signer: &TokenSigner) -> TokenVerifier {
+        signer.public_key().parse().unwrap()
+    }
+
+    #[test]
+    fn valid_token() {
+        let signer = TokenSigner::generate().unwrap();
+        let token = signer
+            .issue(&claims(Role::Admin, TimeDelta::hours(1)))
+            .unwrap();
+        let claims = verifier(&signer).verify(&token).unwrap();
+        assert_eq!(claims.username, "alice");
+        assert_eq!(claims.role, Role::Admin);
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let signer = TokenSigner::generate().unwrap();
+        let user = signer
+            .issue(&claims(Role::User, TimeDelta::hours(1)))
+            .unwrap();
+        let admin = signer
+            .issue(&claims(Role::Admin, TimeDelta::hours(1)))
+            .unwrap();
+        // Claims of one token with the signature of another
+        let (payload, _) = admin.split_once('.').unwrap();
+        let (_, signature) = user.split_once('.').unwrap();
+        let forged = format!("{payload}.{signature}");
+        assert!(verifier(&signer).verify(&forged).is_err());
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let signer = TokenSigner::generate().unwrap();
+        let token = signer
+            .issue(&claims(Role::User, -TimeDelta::seconds(1)))
+            .unwrap();
+        let error = verifier(&signer).verify(&token).unwrap_err();
+        assert!(error.to_string().contains("expired"));
+    }
+
+    #[test]
+    fn token_of_other_key_is_rejected() {
+        let signer = TokenSigner::generate().unwrap();
+        let other = TokenSigner::generate().unwrap();
+        let token = other
+            .issue(&claims(Role::User, TimeDelta::hours(1)))
+            .unwrap();
+        assert!(verifier(&signer).verify(&token).is_err());
+    }
+
+    #[test]
+    fn malformed_token_is_rejected() {
+        let verifier = verifier(&TokenSigner::generate().unwrap());
+        for token in ["", "no dot", "!!!.!!!", "aGVsbG8.c2hvcnQ"] {
+            assert!(verifier.verify(token).is_err(), "{token:?} accepted");
+        }
+    }
+
+    #[test]
+    fn invalid_username_is_not_issued() {
+        let signer = TokenSigner::generate().unwrap();
+        let mut claims = claims(Role::User, TimeDelta::hours(1));
+        claims.username = "not a nickname".to_owned();
+        assert!(signer.issue(&claims).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    io,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    RootCertStore, ServerConfig,
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+use crate::config::TlsConfig;
+
+/// Cryptography used for TLS by both server and clients
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Read every certificate of a PEM file
+pub fn load_certs(path: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>> {
+    let path = path.as_ref();
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Unable to read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {}", path.display())
+    }
+    Ok(certs)
+}
+
+/// Read private key from PEM file
+pub fn load_key(path: impl AsRef<Path>) -> Result<PrivateKeyDer<'static>> {
+    let path = path.as_ref();
+    PrivateKeyDer::from_pem_file(path)
+        .with_context(|| format!("Unable to read private key from {}", path.display()))
+}
+
+/// TLS acceptor using the configured certificate chain and key
+pub fn acceptor(tls: &TlsConfig) -> Result<TlsAcceptor> {
+    let certs = load_certs(&tls.cert)?;
+    let key = load_key(&tls.key)?;
+    log::info!(
+        "TLS certificate fingerprint: {fingerprint}",
+        fingerprint = fingerprint(&certs[0])
+    );
+    let config = ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .context("Unable to select TLS versions")?
+        .with_client_cert_verifier(client_verifier(tls)?)
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Verifier of client certificates signed by the configured client CA, if any
+fn client_verifier(tls: &TlsConfig) -> Result<Arc<dyn ClientCertVerifier>> {
+    let Some(client_ca) = &tls.client_ca else {
+        return Ok(WebPkiClientVerifier::no_client_auth());
+    };
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(client_ca)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Invalid client CA certificate in {}", client_ca.display()))?;
+    }
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), crypto_provider());
+    let builder = if tls.require_client_cert {
+        log::info!(
+            "Client certificates signed by {} required",
+            client_ca.display()
+        );
+        builder
+    } else {
+        log::info!(
+            "Client certificates signed by {} accepted",
+            client_ca.display()
+        );
+        builder.allow_unauthenticated()
+    };
+    builder
+        .build()
+        .context("Unable to set up client certificate verification")
+}
+
+/// DER tag of object identifiers
+const OID_TAG: u8 = 0x06;
+
+/// DER encoded object identifier of the common name attribute, 2.5.4.3
+const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+
+/// DER tags of UTF8String, PrintableString and IA5String, all valid UTF-8
+const STRING_TAGS: &[u8] = &[0x0C, 0x13, 0x16];
+
+/// Split DER element off the front of `input`, returning its tag, content and the rest
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, input) = input.split_first()?;
+    let (len, input) = if first < 0x80 {
+        (usize::from(first), input)
+    } else {
+        // Long form, the low bits giving the number of length bytes
+        let n = usize::from(first & 0x7F);
+        if n == 0 || n > size_of::<usize>() || input.len() < n {
+            return None;
+        }
+        let (len, input) = input.split_at(n);
+        let len = len
+            .iter()
+            .fold(0, |len, byte| (len << 8) | usize::from(*byte));
+        (len, input)
+    };
+    if input.len() < len {
+        return None;
+    }
+    let (content, rest) = input.split_at(len);
+    Some((tag, content, rest))
+}
+
+/// Common name of the subject of a DER certificate
+pub fn common_name(cert: &CertificateDer<'_>) -> Result<String> {
+    let cert =
+        webpki::EndEntityCert::try_from(cert).map_err(|e| anyhow!("Invalid certificate: {e}"))?;
+    // Subject is a sequence of sets of attribute type and value pairs
+    let mut names = cert.subject();
+    while !names.is_empty() {
+        let (_, mut attributes, rest) =
+            der_element(names).context("Malformed certificate subject")?;
+        names = rest;
+        while !attributes.is_empty() {
+            let (_, attribute, rest) =
+                der_element(attributes).context("Malformed certificate subject")?;
+            attributes = rest;
+            let (tag, oid, value) =
+                der_element(attribute).context("Malformed certificate subject")?;
+            if tag != OID_TAG || oid != COMMON_NAME_OID {
+                continue;
+            }
+            let (tag, value, _) = der_element(value).context("Malformed common name")?;
+            if !STRING_TAGS.contains(&tag) {
+                bail!("Unsupported common name encoding")
+            }
+            return String::from_utf8(value.to_vec()).context("Common name is not valid UTF-8");
+        }
+    }
+    bail!("Certificate subject has no common name")
+}
+
+/// Self-signed certificate and its private key
+#[derive(Debug)]
+pub struct SelfSigned {
+    /// Certificate in PEM format
+    pub cert: String,
+    /// Private key in PEM format
+    pub key: String,
+    pub fingerprint: String,
+}
+
+/// Generate self-signed certificate valid for the given host names and addresses
+pub fn generate_self_signed(names: Vec<String>) -> Result<SelfSigned> {
+    let certified = rcgen::generate_simple_self_signed(names)
+        .context("Unable to generate self-signed certificate")?;
+    Ok(SelfSigned {
+        cert: certified.cert.pem(),
+        key: certified.key_pair.serialize_pem(),
+        fingerprint: fingerprint(certified.cert.der()),
+    })
+}
+
+/// SHA-256 fingerprint of a DER certificate as colon separated hex, used for pinning
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Connection accepted from a remote client, encrypted or not
+#[derive(Debug)]
+pub enum Transport {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Transport {
+    /// Remote address
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Transport::Plain(stream) => stream.peer_addr(),
+            Transport::Tls(stream) => stream.get_ref().0.peer_addr(),
+        }
+    }
+
+    /// Whether the connection is encrypted
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Transport::Tls(_))
+    }
+
+    /// Common name of the client certificate, if the client presented one.
+    /// Only certificates signed by the configured client CA pass the TLS handshake.
+    pub fn client_identity(&self) -> Result<Option<String>> {
+        let Transport::Tls(stream) = self else {
+            return Ok(None);
+        };
+        stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(common_name)
+            .transpose()
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{BmpString, CertificateParams, DistinguishedName, DnType, DnValue, KeyPair};
+
+    use super::*;
+
+    /// Self-signed certificate with the given subject attributes
+    fn certificate(subject: &[(DnType, DnValue)]) -> CertificateDer<'static> {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        for (ty, value) in subject {
+            params.distinguished_name.push(ty.clone(), value.clone());
+        }
+        let key = KeyPair::generate().unwrap();
+        params.self_signed(&key).unwrap().der().clone()
+    }
+
+    #[test]
+    fn der_element_lengths() {
+        assert_eq!(
+            der_element(&[0x04, 0x02, 1, 2, 3]),
+            Some((0x04, &[1, 2][..], &[3][..]))
+        );
+        let mut long = vec![0x04, 0x82, 0x01, 0x00];
+        long.extend([7; 256]);
+        assert_eq!(der_element(&long), Some((0x04, &[7; 256][..], &[][..])));
+        // Truncated content, missing length bytes and indefinite length
+        assert_eq!(der_element(&[0x04, 0x03, 1, 2]), None);
+        assert_eq!(der_element(&[0x04, 0x82, 0x01]), None);
+        assert_eq!(der_element(&[0x04, 0x80, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn common_name_among_other_attributes() {
+        let cert = certificate(&[
+            (DnType::CountryName, "NL".into()),
+            (DnType::OrganizationName, "Example".into()),
+            (DnType::CommonName, "alice".into()),
+            (DnType::OrganizationalUnitName, "Ops".into()),
+        ]);
+        assert_eq!(common_name(&cert).unwrap(), "alice");
+    }
+
+    #[test]
+    fn common_name_after_long_attribute() {
+        // Subject and attribute lengths over 127 bytes take the long form
+        let cert = certificate(&[
+            (DnType::OrganizationName, "x".repeat(300).into()),
+            (
+                DnType::CommonName,
+                DnValue::PrintableString("bob".try_into().unwrap()),
+            ),
+        ]);
+        assert_eq!(common_name(&cert).unwrap(), "bob");
+    }
+
+    #[test]
+    fn missing_common_name() {
+        let cert = certificate(&[(DnType::OrganizationName, "Example".into())]);
+        assert!(common_name(&cert).is_err());
+    }
+
+    #[test]
+    fn unsupported_common_name_encoding() {
+        let name = BmpString::try_from("carol").unwrap();
+        let cert = certificate(&[(DnType::CommonName, DnValue::BmpString(name))]);
+        let error = common_name(&cert).unwrap_err();
+        assert_eq!(error.to_string(), "Unsupported common name encoding");
+    }
+}
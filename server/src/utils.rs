@@ -1,9 +1,17 @@
 use std::{
     collections::{hash_map, HashMap},
     fmt::Display,
+    fs,
     hash::Hash,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
 };
 
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::TimeDelta;
+use serde::{de::DeserializeOwned, Serialize};
+
 const SAFE_MODE: bool = false;
 
 pub struct Sensitive<T: Display>(pub T);
@@ -27,3 +35,134 @@ where
         hash_map::Entry::Vacant(entry) => entry.insert(new_value),
     }
 }
+
+/// Longest duration accepted for bans, mutes, lockouts and grace periods, about 100 years,
+/// keeping times computed from it far within the range of `DateTime`
+pub const MAX_DURATION: TimeDelta = TimeDelta::days(36_525);
+
+/// Check duration does not exceed `MAX_DURATION`
+pub fn check_duration(duration: TimeDelta) -> Result<TimeDelta> {
+    if duration > MAX_DURATION {
+        bail!(
+            "Duration must not exceed {days} days",
+            days = MAX_DURATION.num_days()
+        )
+    }
+    Ok(duration)
+}
+
+/// Parse duration such as `90`, `30s`, `10m`, `2h` or `7d`. Plain numbers are seconds.
+pub fn parse_duration(s: &str) -> Result<TimeDelta> {
+    let (number, unit) = s
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or((s, ""), |i| s.split_at(i));
+    let number: i64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid duration {s}"))?;
+    let multiplier = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("Invalid duration unit {unit}, expected s, m, h or d"),
+    };
+    let duration = number
+        .checked_mul(multiplier)
+        .and_then(TimeDelta::try_seconds)
+        .filter(|duration| *duration > TimeDelta::zero())
+        .ok_or(anyhow!("Invalid duration {s}"))?;
+    check_duration(duration)
+}
+
+/// Write file readable by its owner only
+pub fn write_private(path: &Path, content: &str) -> io::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(content.as_bytes())
+}
+
+/// Read `what` from TOML file, or `None` if the file does not exist yet
+pub fn load_toml<T: DeserializeOwned>(path: &Path, what: &str) -> Result<Option<T>> {
+    match fs::read_to_string(path) {
+        Ok(content) => toml::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("Invalid {what} file {}", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Unable to read {what} file {}", path.display())),
+    }
+}
+
+/// Save `what` to TOML file
+pub fn save_toml(path: &Path, what: &str, value: &impl Serialize) -> Result<()> {
+    let content =
+        toml::to_string_pretty(value).with_context(|| format!("Unable to serialize {what}"))?;
+    // Write whole file aside first so a crash never leaves it truncated
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, content)
+        .and_then(|()| fs::rename(&temp_path, path))
+        .with_context(|| format!("Unable to save {what} to {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("90").unwrap(), TimeDelta::seconds(90));
+        assert_eq!(parse_duration("30s").unwrap(), TimeDelta::seconds(30));
+        assert_eq!(parse_duration("10m").unwrap(), TimeDelta::minutes(10));
+        assert_eq!(parse_duration("2h").unwrap(), TimeDelta::hours(2));
+        assert_eq!(parse_duration("7d").unwrap(), TimeDelta::days(7));
+    }
+
+    #[test]
+    fn parse_duration_rejects_invalid() {
+        for invalid in [
+            "",
+            "s",
+            "0",
+            "0s",
+            "-5",
+            "5w",
+            "1.5h",
+            "10 m",
+            "99999999999999999d",
+        ] {
+            assert!(parse_duration(invalid).is_err(), "{invalid:?} accepted");
+        }
+    }
+
+    #[test]
+    fn parse_duration_rejects_too_long() {
+        // Valid number of seconds, but adding it to the current time would overflow
+        assert!(parse_duration("1000000000d").is_err());
+        assert!(parse_duration("36526d").is_err());
+        assert_eq!(parse_duration("36525d").unwrap(), MAX_DURATION);
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let dir = std::env::temp_dir().join(format!("utils-toml-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("values.toml");
+
+        assert!(load_toml::<BTreeMap<String, u32>>(&path, "values")
+            .unwrap()
+            .is_none());
+        let values = BTreeMap::from([("a".to_owned(), 1), ("b".to_owned(), 2)]);
+        save_toml(&path, "values", &values).unwrap();
+        assert_eq!(load_toml(&path, "values").unwrap(), Some(values));
+        assert!(!path.with_extension("tmp").exists());
+
+        fs::write(&path, "not = [valid").unwrap();
+        assert!(load_toml::<BTreeMap<String, u32>>(&path, "values").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}